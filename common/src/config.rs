@@ -0,0 +1,121 @@
+//! Network settings kept in a flash sector of their own rather than in the firmware, so
+//! that credentials never end up in git.
+//!
+//! The sector holds `MAGIC`, the little endian format `VERSION` and payload length, the
+//! serialised `DeviceConfig` and the little endian CRC-16 of everything before it.
+
+use serde::{Deserialize, Serialize};
+
+use crate::framing::crc16;
+
+/// Offset of the config sector from the start of flash, the last sector of the 2 MiB
+/// that `firmware/memory.x` assumes.
+pub const FLASH_OFFSET: u32 = 0x1F_F000;
+
+/// Where the config sector is mapped in the RP2350 address space.
+pub const FLASH_ADDRESS: u32 = 0x1000_0000 + FLASH_OFFSET;
+
+pub const SECTOR_LEN: usize = 4096;
+
+/// Longest record, which is all that is read from the sector.
+pub const MAX_RECORD_LEN: usize = 256;
+
+pub const MAGIC: [u8; 4] = *b"PNDC";
+
+/// Bumped whenever the encoding of `DeviceConfig` changes.
+pub const VERSION: u16 = 2;
+
+const HEADER_LEN: usize = MAGIC.len() + 4;
+
+pub const DEFAULT_PORT: u16 = 1234;
+
+pub const DEFAULT_NAME: &str = "pendulum";
+
+/// WPA2 network the device starts when it cannot join the configured one, or has none.
+/// Anyone on it can send commands, so it is protected by `DeviceConfig::ap_passphrase`.
+pub const AP_SSID: &str = "pendulum";
+/// Passphrase of the access point of a device without a config. It is public, so it
+/// only protects a board on the bench until `receiver write-config` has been used.
+pub const DEFAULT_AP_PASSPHRASE: &str = "pendulum-setup";
+pub const AP_CHANNEL: u8 = 6;
+/// The device's own address on its access point, where it serves `DEFAULT_PORT` unless
+/// configured otherwise. Clients get addresses from `dhcp::Server`.
+pub const AP_ADDRESS: [u8; 4] = [192, 168, 4, 1];
+pub const AP_PREFIX_LEN: u8 = 24;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub ssid: heapless::String<32>,
+    pub passphrase: heapless::String<64>,
+    /// Passphrase of the device's own access point, see `AP_SSID`.
+    pub ap_passphrase: heapless::String<64>,
+    pub ip: IpConfig,
+    /// Port of the telemetry server.
+    pub port: u16,
+    /// Name the device announces itself with.
+    pub name: heapless::String<32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpConfig {
+    Dhcp,
+    Static {
+        address: [u8; 4],
+        prefix_len: u8,
+        gateway: Option<[u8; 4]>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The sector is erased, no config has been written.
+    Blank,
+    /// The sector holds something else.
+    Magic,
+    /// Written in a format version this build does not know.
+    Version(u16),
+    Crc,
+    Decode,
+}
+
+impl DeviceConfig {
+    /// Writes the record to `out` and returns its length.
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let out = out.get_mut(..MAX_RECORD_LEN)?;
+        let (header, rest) = out.split_at_mut(HEADER_LEN);
+        let payload_len =
+            bincode::serde::encode_into_slice(self, rest, bincode::config::standard()).ok()?;
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        header[6..8].copy_from_slice(&(payload_len as u16).to_le_bytes());
+
+        let len = HEADER_LEN + payload_len;
+        let crc = crc16(&out[..len]);
+        out.get_mut(len..len + 2)?
+            .copy_from_slice(&crc.to_le_bytes());
+        Some(len + 2)
+    }
+
+    /// Reads the record at the start of `sector`.
+    pub fn decode(sector: &[u8]) -> Result<Self, ConfigError> {
+        let header = sector.get(..HEADER_LEN).ok_or(ConfigError::Decode)?;
+        if header[..4] == [0xFF; 4] {
+            return Err(ConfigError::Blank);
+        }
+        if header[..4] != MAGIC {
+            return Err(ConfigError::Magic);
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            return Err(ConfigError::Version(version));
+        }
+        let len = HEADER_LEN + u16::from_le_bytes([header[6], header[7]]) as usize;
+        let crc = sector.get(len..len + 2).ok_or(ConfigError::Decode)?;
+        if crc16(&sector[..len]) != u16::from_le_bytes([crc[0], crc[1]]) {
+            return Err(ConfigError::Crc);
+        }
+        bincode::serde::decode_from_slice(&sector[HEADER_LEN..len], bincode::config::standard())
+            .map(|(config, _)| config)
+            .map_err(|_| ConfigError::Decode)
+    }
+}
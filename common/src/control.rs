@@ -0,0 +1,311 @@
+use core::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::filter::{EKF, GRAVITY, Mat, NLModel, RADIUS, WHEEL_STATIC_GAIN};
+use crate::hal::{Clock, Motor, RotaryEncoder};
+use crate::safety::{Fault, OutputGuard, SafetyLimits};
+use crate::timing::LoopTimer;
+use crate::{CommandError, CommandMessage, ControllerMessage, CovarianceMessage, StatusMessage};
+use crate::{SAMPLE_TIME_MS, TimingMessage};
+
+/// Returns `a - b` wrapped to [-PI, PI].
+pub fn sub_angles(a: f32, b: f32) -> f32 {
+    let mut diff_angle = a - b;
+    while diff_angle < -PI {
+        diff_angle += 2.0 * PI;
+    }
+    while diff_angle > PI {
+        diff_angle -= 2.0 * PI;
+    }
+    diff_angle
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BalancingState {
+    Swinging,
+    Chilling,
+    Balancing,
+}
+
+/// What drives the motor in `ControlLoop`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    /// Motor off.
+    Stopped,
+    /// Swing-up and balancing by `Controller`.
+    Balancing,
+    /// Motor held at a fixed output, for characterising the motor.
+    Bench(f32),
+}
+
+/// State feedback gains for balancing, the output is `u = -K x`.
+pub const DEFAULT_GAINS: [f32; 3] = [-0.00582551, -8.00347, -0.967164];
+
+/// Estimates `x = [wheel velocity, pendulum angle, pendulum velocity]` from the
+/// pendulum angle, where an angle of 0 is upright and PI is hanging down.
+pub struct Estimator {
+    pub ekf: EKF<3, 1, NLModel>,
+}
+
+impl Estimator {
+    /// Starts with the pendulum hanging down and at rest.
+    pub fn new(dt: f32) -> Self {
+        let mut ekf = EKF::from_model(NLModel { dt });
+        ekf.x[1] = PI;
+        Estimator { ekf }
+    }
+
+    /// Advances the estimate one sample given the output `u` applied during the last
+    /// sample and the measured `angle`, if the read succeeded.
+    pub fn update(&mut self, u: f32, angle: Option<f32>) {
+        let ekf = &mut self.ekf;
+        ekf.time_update(u);
+
+        if let Some(angle) = angle {
+            let pred = ekf.x[1];
+            ekf.measurment_update_from_error([sub_angles(angle, pred)].into());
+        }
+
+        while ekf.x[1] < -PI {
+            ekf.x[1] += 2.0 * PI
+        }
+        while ekf.x[1] > PI {
+            ekf.x[1] -= 2.0 * PI
+        }
+    }
+
+    pub fn x(&self) -> Mat<3, 1> {
+        self.ekf.x
+    }
+
+    pub fn covariance(&self) -> [[f32; 3]; 3] {
+        let p = self.ekf.P;
+        core::array::from_fn(|row| core::array::from_fn(|column| p[(row, column)]))
+    }
+}
+
+/// Energy based swing-up followed by state feedback once the pendulum is close to upright.
+pub struct Controller {
+    pub gains: [f32; 3],
+    pub state: BalancingState,
+}
+
+impl Controller {
+    pub fn new(gains: [f32; 3]) -> Self {
+        Controller {
+            gains,
+            state: BalancingState::Swinging,
+        }
+    }
+
+    /// Returns the requested motor output given the estimated state `x` and the output
+    /// currently applied to the motor.
+    pub fn update(&mut self, x: Mat<3, 1>, output: f32) -> f32 {
+        let f: Mat<1, 3> = Mat::from_column_slice(&self.gains);
+        let top_energy = RADIUS * GRAVITY;
+        let bot_energy = -RADIUS * GRAVITY;
+        let cur_energy = RADIUS * GRAVITY * libm::cosf(x[1]) + RADIUS * x[2] * RADIUS * x[2] / 2.0;
+
+        let command;
+        self.state = match self.state {
+            BalancingState::Swinging => {
+                command = if x[0].abs() > WHEEL_STATIC_GAIN * 0.2 {
+                    x[2].signum() * 0.15
+                } else if cur_energy < top_energy {
+                    -x[2].signum() * 0.2
+                } else if cur_energy > top_energy {
+                    x[2].signum() * 0.2
+                } else {
+                    x[0] / WHEEL_STATIC_GAIN
+                };
+
+                let u = (-f * x)[0];
+                if sub_angles(x[1], 0.0).abs() < 0.2 && u.abs() < 3.0 {
+                    BalancingState::Balancing
+                } else {
+                    BalancingState::Swinging
+                }
+            }
+            BalancingState::Chilling => {
+                let boundary = 0.7;
+                if cur_energy > boundary * top_energy + (1.0 - boundary) * bot_energy {
+                    command = x[2].signum() * 0.3;
+                    BalancingState::Chilling
+                } else {
+                    command = output;
+                    BalancingState::Swinging
+                }
+            }
+            BalancingState::Balancing => {
+                let u = (-f * x)[0];
+                command = u.clamp(-1.0, 1.0);
+                if sub_angles(x[1], 0.0).abs() > 0.25 {
+                    BalancingState::Chilling
+                } else {
+                    BalancingState::Balancing
+                }
+            }
+        };
+        command
+    }
+}
+
+/// What happened during one iteration of `ControlLoop::step`.
+pub struct Step {
+    pub message: ControllerMessage,
+    /// Set once every `TIMING_WINDOW` iterations.
+    pub timing: Option<TimingMessage>,
+    /// Set on the iteration where the guard latched a fault.
+    pub new_fault: Option<Fault>,
+    /// Set once every `COVARIANCE_INTERVAL` iterations.
+    pub covariance: Option<CovarianceMessage>,
+}
+
+/// Number of iterations summarised in each `TimingMessage`.
+pub const TIMING_WINDOW: u32 = 100;
+
+/// Number of iterations between covariance snapshots.
+pub const COVARIANCE_INTERVAL: u32 = 10;
+
+/// The balancing control loop, independent of the hardware it runs on.
+pub struct ControlLoop<M, E, C> {
+    pub motor: M,
+    pub encoder: E,
+    pub clock: C,
+    /// Encoder reading when the pendulum is upright.
+    pub ref_angle: f32,
+    pub estimator: Estimator,
+    pub controller: Controller,
+    pub guard: OutputGuard,
+    pub timer: LoopTimer,
+    pub mode: Mode,
+    dt: f32,
+    ticks: u32,
+}
+
+impl<M, E, C> ControlLoop<M, E, C>
+where
+    M: Motor,
+    E: RotaryEncoder,
+    C: Clock,
+{
+    pub fn new(motor: M, encoder: E, clock: C, ref_angle: f32) -> Self {
+        let dt = SAMPLE_TIME_MS as f32 * 1e-3;
+        ControlLoop {
+            motor,
+            encoder,
+            clock,
+            ref_angle,
+            estimator: Estimator::new(dt),
+            controller: Controller::new(DEFAULT_GAINS),
+            guard: OutputGuard::new(SafetyLimits::default()),
+            timer: LoopTimer::new(SAMPLE_TIME_MS as u64 * 1000, TIMING_WINDOW),
+            mode: Mode::Balancing,
+            dt,
+            ticks: 0,
+        }
+    }
+
+    /// Waits for the next tick and runs one iteration of the loop.
+    pub async fn step(&mut self) -> Step {
+        self.clock.tick().await;
+        let start_us = self.clock.now_us();
+        self.timer.start(start_us);
+
+        let reading = self.encoder.rotation().await;
+        self.timer
+            .encoder_read(self.clock.now_us().saturating_sub(start_us));
+
+        let angle = reading
+            .ok()
+            .map(|raw_angle| sub_angles(raw_angle, self.ref_angle));
+        self.estimator.update(self.motor.output(), angle);
+        let x = self.estimator.x();
+
+        let command = match self.mode {
+            Mode::Stopped => 0.0,
+            Mode::Balancing => self.controller.update(x, self.motor.output()),
+            Mode::Bench(output) => output,
+        };
+
+        let prev_fault = self.guard.fault();
+        self.motor
+            .set_output(self.guard.update(command, x[0], self.dt));
+        let new_fault = match (prev_fault, self.guard.fault()) {
+            (None, Some(fault)) => Some(fault),
+            _ => None,
+        };
+
+        let message = ControllerMessage {
+            time_ms: start_us / 1000,
+            state: self.controller.state,
+            control: self.motor.output(),
+            sensor_pend_angle: angle.unwrap_or(f32::NAN),
+            sensor_wheel_velocity: f32::NAN,
+            pend_angle: x[1],
+            pend_velocity: x[2],
+            wheel_velocity: x[0],
+        };
+        let covariance =
+            self.ticks
+                .is_multiple_of(COVARIANCE_INTERVAL)
+                .then(|| CovarianceMessage {
+                    time_ms: message.time_ms,
+                    p: self.estimator.covariance(),
+                });
+        self.ticks = self.ticks.wrapping_add(1);
+        let timing = self.timer.end(self.clock.now_us());
+
+        Step {
+            message,
+            timing,
+            new_fault,
+            covariance,
+        }
+    }
+
+    /// Carries out `command`, taking effect from the next iteration. Commands that do not
+    /// concern the control loop are accepted and ignored.
+    pub fn apply(&mut self, command: &CommandMessage) -> Result<(), CommandError> {
+        match *command {
+            CommandMessage::StopMotor => self.mode = Mode::Stopped,
+            CommandMessage::StartSwingUp => {
+                self.guard.reset();
+                self.controller.state = BalancingState::Swinging;
+                self.mode = Mode::Balancing;
+            }
+            CommandMessage::EnterBenchMode => self.mode = Mode::Bench(0.0),
+            CommandMessage::SetMotorOutput(output) => {
+                if !matches!(self.mode, Mode::Bench(_)) {
+                    return Err(CommandError::NotInBenchMode);
+                }
+                if !(-1.0..=1.0).contains(&output) {
+                    return Err(CommandError::InvalidValue);
+                }
+                self.mode = Mode::Bench(output);
+            }
+            CommandMessage::SetGains(gains) => {
+                if !gains.iter().all(|k| k.is_finite()) {
+                    return Err(CommandError::InvalidValue);
+                }
+                self.controller.gains = gains;
+            }
+            CommandMessage::RequestStatus
+            | CommandMessage::SetTransport(_)
+            | CommandMessage::Ping { .. } => {}
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> StatusMessage {
+        StatusMessage {
+            time_ms: self.clock.now_us() / 1000,
+            mode: self.mode,
+            state: self.controller.state,
+            fault: self.guard.fault(),
+            gains: self.controller.gains,
+            output: self.motor.output(),
+        }
+    }
+}
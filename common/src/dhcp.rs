@@ -0,0 +1,175 @@
+//! Just enough of a DHCP server for a laptop to join the device's access point: every
+//! client gets an address on `config::AP_ADDRESS`'s subnet and no router, so it keeps
+//! its other routes.
+
+use crate::config::{AP_ADDRESS, AP_PREFIX_LEN};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+/// Longest reply, no options beyond the handful the server sends.
+pub const MAX_REPLY_LEN: usize = 300;
+
+/// Clients that can hold an address at once. Beyond that the oldest lease is reused.
+pub const MAX_LEASES: usize = 4;
+
+const LEASE_SECONDS: u32 = 24 * 60 * 60;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_START: usize = 240;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const RELEASE: u8 = 7;
+
+/// Hands out `AP_ADDRESS` + 1 to `AP_ADDRESS` + `MAX_LEASES`, one per hardware address.
+pub struct Server {
+    leases: [Option<[u8; 6]>; MAX_LEASES],
+    /// Lease reused when all are taken.
+    next_eviction: usize,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Server {
+    pub const fn new() -> Self {
+        Server {
+            leases: [None; MAX_LEASES],
+            next_eviction: 0,
+        }
+    }
+
+    fn address(slot: usize) -> [u8; 4] {
+        let [a, b, c, d] = AP_ADDRESS;
+        [a, b, c, d + 1 + slot as u8]
+    }
+
+    fn lease(&mut self, client: [u8; 6]) -> [u8; 4] {
+        let slot = match self.leases.iter().position(|&lease| lease == Some(client)) {
+            Some(slot) => slot,
+            None => {
+                let slot = match self.leases.iter().position(Option::is_none) {
+                    Some(slot) => slot,
+                    None => {
+                        let slot = self.next_eviction;
+                        self.next_eviction = (slot + 1) % MAX_LEASES;
+                        slot
+                    }
+                };
+                self.leases[slot] = Some(client);
+                slot
+            }
+        };
+        Self::address(slot)
+    }
+
+    /// Handles a message received on `SERVER_PORT` and writes the reply, to be broadcast
+    /// to `CLIENT_PORT`, to `out`. Returns the length of the reply, if any.
+    pub fn handle(&mut self, request: &[u8], out: &mut [u8]) -> Option<usize> {
+        if request.len() < OPTIONS_START
+            || request[0] != BOOTREQUEST
+            || request[1..3] != [1, 6]
+            || request[236..240] != COOKIE
+        {
+            return None;
+        }
+        let client: [u8; 6] = request[28..34].try_into().unwrap();
+        let options = &request[OPTIONS_START..];
+        let message_type = *find_option(options, OPTION_MESSAGE_TYPE)?.first()?;
+        if let Some(server) = find_option(options, OPTION_SERVER_ID)
+            && server != AP_ADDRESS
+        {
+            // The client chose another server.
+            return None;
+        }
+
+        let (reply_type, address) = match message_type {
+            DISCOVER => (OFFER, self.lease(client)),
+            REQUEST => {
+                let address = self.lease(client);
+                let requested =
+                    find_option(options, OPTION_REQUESTED_ADDRESS).unwrap_or(&request[12..16]);
+                if requested == address {
+                    (ACK, address)
+                } else {
+                    (NAK, [0; 4])
+                }
+            }
+            RELEASE => {
+                for lease in &mut self.leases {
+                    if *lease == Some(client) {
+                        *lease = None;
+                    }
+                }
+                return None;
+            }
+            _ => return None,
+        };
+        encode_reply(request, reply_type, address, out)
+    }
+}
+
+fn find_option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+    loop {
+        match *options.first()? {
+            OPTION_END => return None,
+            OPTION_PAD => options = &options[1..],
+            found => {
+                let len = *options.get(1)? as usize;
+                let value = options.get(2..2 + len)?;
+                if found == code {
+                    return Some(value);
+                }
+                options = &options[2 + len..];
+            }
+        }
+    }
+}
+
+fn encode_reply(request: &[u8], reply_type: u8, address: [u8; 4], out: &mut [u8]) -> Option<usize> {
+    let out = out.get_mut(..MAX_REPLY_LEN)?;
+    out.fill(0);
+    out[0] = BOOTREPLY;
+    // Hardware type and length, transaction id, seconds and flags as requested.
+    out[1..12].copy_from_slice(&request[1..12]);
+    out[3] = 0;
+    out[16..20].copy_from_slice(&address);
+    out[20..24].copy_from_slice(&AP_ADDRESS);
+    out[24..28].copy_from_slice(&request[24..28]);
+    out[28..44].copy_from_slice(&request[28..44]);
+    out[236..240].copy_from_slice(&COOKIE);
+
+    let mask = u32::MAX << (32 - AP_PREFIX_LEN);
+    let mut at = OPTIONS_START;
+    let mut put = |code: u8, value: &[u8]| {
+        out[at] = code;
+        out[at + 1] = value.len() as u8;
+        out[at + 2..at + 2 + value.len()].copy_from_slice(value);
+        at += 2 + value.len();
+    };
+    put(OPTION_MESSAGE_TYPE, &[reply_type]);
+    put(OPTION_SERVER_ID, &AP_ADDRESS);
+    if reply_type != NAK {
+        put(OPTION_LEASE_TIME, &LEASE_SECONDS.to_be_bytes());
+        put(OPTION_SUBNET_MASK, &mask.to_be_bytes());
+    }
+    out[at] = OPTION_END;
+    Some(MAX_REPLY_LEN)
+}
@@ -0,0 +1,59 @@
+//! Devices broadcast an `Announcement` to `PORT` every `ANNOUNCE_INTERVAL_MS`, so a
+//! receiver can find them without knowing the address DHCP handed out.
+//!
+//! A datagram is `MAGIC` followed by the announcement, serialised like the messages on
+//! the link. The address to connect to is the datagram's source address.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{PROTOCOL_VERSION, framing::FrameError, truncated};
+
+/// Port the announcements are broadcast to.
+pub const PORT: u16 = 1235;
+
+pub const ANNOUNCE_INTERVAL_MS: u64 = 1000;
+
+/// Tells announcements apart from anything else broadcast to `PORT`.
+pub const MAGIC: [u8; 4] = *b"PNDL";
+
+/// Longest announcement datagram.
+pub const MAX_DATAGRAM_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    /// Kept first so that devices speaking another version can still be listed.
+    pub protocol_version: u16,
+    pub name: heapless::String<32>,
+    /// Port of the device's TCP server.
+    pub port: u16,
+}
+
+impl Announcement {
+    /// Announces a device speaking this version of the protocol. A name that is too
+    /// long is truncated.
+    pub fn new(name: &str, port: u16) -> Self {
+        Announcement {
+            protocol_version: PROTOCOL_VERSION,
+            name: truncated(name),
+            port,
+        }
+    }
+
+    /// Writes the datagram to `out` and returns its length.
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let body = out.get_mut(MAGIC.len()..)?;
+        let n = bincode::serde::encode_into_slice(self, body, bincode::config::standard()).ok()?;
+        out[..MAGIC.len()].copy_from_slice(&MAGIC);
+        Some(MAGIC.len() + n)
+    }
+
+    /// Reads a received datagram, `None` if it is not an announcement.
+    pub fn parse(datagram: &[u8]) -> Option<Result<Self, FrameError>> {
+        let body = datagram.strip_prefix(&MAGIC)?;
+        Some(
+            bincode::serde::decode_from_slice(body, bincode::config::standard())
+                .map(|(announcement, _)| announcement)
+                .map_err(|_| FrameError::Decode),
+        )
+    }
+}
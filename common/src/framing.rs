@@ -0,0 +1,170 @@
+//! Self-synchronising framing for the telemetry link.
+//!
+//! A frame is the payload followed by its little endian CRC-16, COBS encoded so that it
+//! contains no zero bytes, and terminated by a zero byte. A reader that loses track of
+//! the stream picks it up again at the next zero byte.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+pub const DELIMITER: u8 = 0;
+
+/// Longest encoded message sent over the link, the same for `HelloMessage`, `Envelope`
+/// and `CommandRequest`. `tests/message_size.rs` checks that every message fits.
+pub const MAX_MESSAGE_LEN: usize = 256;
+
+/// Longest frame on the link.
+pub const MAX_FRAME_LEN: usize = max_frame_len(MAX_MESSAGE_LEN);
+
+/// Size of the largest frame that can carry `payload_len` bytes.
+pub const fn max_frame_len(payload_len: usize) -> usize {
+    let data_len = payload_len + 2;
+    data_len + data_len / 254 + 2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame did not fit in the decode buffer.
+    Overflow,
+    /// The frame was not valid COBS.
+    Cobs,
+    /// The checksum did not match.
+    Crc,
+    /// The payload was not a valid message.
+    Decode,
+}
+
+/// CRC-16/CCITT-FALSE.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Writes `payload` as a frame to `out`, returns the length of the frame or `None` if
+/// `out` is too small.
+pub fn encode(payload: &[u8], out: &mut [u8]) -> Option<usize> {
+    let crc = crc16(payload).to_le_bytes();
+    let mut code_index = 0;
+    let mut write = 1;
+    let mut code = 1_u8;
+    for &byte in payload.iter().chain(crc.iter()) {
+        if byte == 0 {
+            *out.get_mut(code_index)? = code;
+            code_index = write;
+            write += 1;
+            code = 1;
+        } else {
+            *out.get_mut(write)? = byte;
+            write += 1;
+            code += 1;
+            if code == 0xFF {
+                *out.get_mut(code_index)? = code;
+                code_index = write;
+                write += 1;
+                code = 1;
+            }
+        }
+    }
+    *out.get_mut(code_index)? = code;
+    *out.get_mut(write)? = DELIMITER;
+    Some(write + 1)
+}
+
+/// Serialises `msg` and writes it as a frame to `out`, using `scratch` for the payload.
+pub fn encode_message<T: Serialize>(msg: &T, scratch: &mut [u8], out: &mut [u8]) -> Option<usize> {
+    let n = bincode::serde::encode_into_slice(msg, scratch, bincode::config::standard()).ok()?;
+    encode(&scratch[..n], out)
+}
+
+pub fn decode_message<T: DeserializeOwned>(payload: &[u8]) -> Result<T, FrameError> {
+    bincode::serde::decode_from_slice(payload, bincode::config::standard())
+        .map(|(msg, _)| msg)
+        .map_err(|_| FrameError::Decode)
+}
+
+/// Decodes COBS in place and returns the decoded length.
+fn cobs_decode(buf: &mut [u8]) -> Option<usize> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < buf.len() {
+        let code = buf[read] as usize;
+        read += 1;
+        if code == 0 || read + code - 1 > buf.len() {
+            return None;
+        }
+        buf.copy_within(read..read + code - 1, write);
+        read += code - 1;
+        write += code - 1;
+        if code < 0xFF && read < buf.len() {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+    Some(write)
+}
+
+/// Splits a byte stream into frames of at most `N` encoded bytes.
+pub struct Decoder<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    overflow: bool,
+}
+
+impl<const N: usize> Default for Decoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Decoder<N> {
+    pub const fn new() -> Self {
+        Decoder {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Feeds one byte from the stream. Returns the payload or the reason it was rejected
+    /// when `byte` ends a frame.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], FrameError>> {
+        if byte != DELIMITER {
+            if self.len < N {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflow) {
+            return Some(Err(FrameError::Overflow));
+        }
+        if len == 0 {
+            return None;
+        }
+        let Some(len) = cobs_decode(&mut self.buf[..len]) else {
+            return Some(Err(FrameError::Cobs));
+        };
+        if len < 2 {
+            return Some(Err(FrameError::Cobs));
+        }
+        let (payload, crc) = self.buf[..len].split_at(len - 2);
+        if crc16(payload).to_le_bytes() != crc {
+            return Some(Err(FrameError::Crc));
+        }
+        Some(Ok(payload))
+    }
+}
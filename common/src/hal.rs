@@ -0,0 +1,27 @@
+//! Device interfaces used by the control loop, implemented by the firmware drivers and by
+//! the simulated rig on the host.
+
+pub trait RotaryEncoder {
+    type Error;
+
+    /// Returns rotation in radians
+    #[allow(async_fn_in_trait)]
+    async fn rotation(&mut self) -> Result<f32, Self::Error>;
+}
+
+pub trait Motor {
+    /// `output` should be a value in the interval [-1.0, 1.0]
+    fn set_output(&mut self, output: f32);
+
+    /// The output that was last set.
+    fn output(&self) -> f32;
+}
+
+pub trait Clock {
+    /// Waits until the next control loop tick.
+    #[allow(async_fn_in_trait)]
+    async fn tick(&mut self);
+
+    /// Returns microseconds since an arbitrary fixed point.
+    fn now_us(&self) -> u64;
+}
@@ -3,8 +3,19 @@
 use serde::{Deserialize, Serialize};
 
 pub const SAMPLE_TIME_MS: u32 = 10;
+
+/// Bumped whenever the encoding of the messages sent over the link changes.
+pub const PROTOCOL_VERSION: u16 = 5;
+pub mod config;
+pub mod control;
+pub mod dhcp;
+pub mod discovery;
 mod ekf;
+pub mod framing;
+pub mod hal;
 mod model;
+pub mod safety;
+pub mod timing;
 pub mod filter {
     pub use crate::ekf::*;
     pub use crate::model::*;
@@ -14,11 +25,94 @@ pub mod external {
     pub use nalgebra;
 }
 
+/// First frame on every connection, followed by `Envelope`s.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelloMessage {
+    /// Kept as the first field so that any version can read it, see `peek_version`.
+    pub protocol_version: u16,
+    /// Name of the firmware binary, like `main`.
+    pub binary: heapless::String<16>,
+    /// Git revision the binary was built from.
+    pub build_id: heapless::String<32>,
+    pub sample_time_ms: u32,
+    pub model: ModelConstants,
+    pub gains: [f32; 3],
+}
+
+impl HelloMessage {
+    /// Describes a binary built from this version of `common` running with `gains`.
+    /// Names that are too long are truncated.
+    pub fn new(binary: &str, build_id: &str, gains: [f32; 3]) -> Self {
+        HelloMessage {
+            protocol_version: PROTOCOL_VERSION,
+            binary: truncated(binary),
+            build_id: truncated(build_id),
+            sample_time_ms: SAMPLE_TIME_MS,
+            model: ModelConstants::BUILT_IN,
+            gains,
+        }
+    }
+
+    /// Reads the protocol version from an encoded `HelloMessage` of any version.
+    pub fn peek_version(payload: &[u8]) -> Result<u16, framing::FrameError> {
+        framing::decode_message(payload)
+    }
+}
+
+fn truncated<const N: usize>(s: &str) -> heapless::String<N> {
+    let mut out = heapless::String::new();
+    for c in s.chars() {
+        if out.push(c).is_err() {
+            break;
+        }
+    }
+    out
+}
+
+/// The physical constants of `filter::NLModel`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ModelConstants {
+    pub wheel_time_constant: f32,
+    pub wheel_static_gain: f32,
+    pub inertia_ratio: f32,
+    pub radius: f32,
+    pub gravity: f32,
+}
+
+impl ModelConstants {
+    pub const BUILT_IN: Self = ModelConstants {
+        wheel_time_constant: filter::WHEEL_TIME_CONSTANT,
+        wheel_static_gain: filter::WHEEL_STATIC_GAIN,
+        inertia_ratio: filter::INERTIA_RATIO,
+        radius: filter::RADIUS,
+        gravity: filter::GRAVITY,
+    };
+}
+
+/// A `LogMessage` as sent over the link, with what the receiver needs to notice losses.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    /// Assigned when the message is queued for this client, consecutive unless messages
+    /// were lost. Starts over on every connection.
+    pub seq: u32,
+    /// Messages dropped on the device since this client connected because its transmit
+    /// queue was full.
+    pub dropped: u32,
+    pub msg: LogMessage,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum LogMessage {
     Controller(ControllerMessage),
     Bench(BenchMessage),
+    Fault(FaultMessage),
+    Timing(TimingMessage),
     Alive,
+    Status(StatusMessage),
+    Ack(AckMessage),
+    Covariance(CovarianceMessage),
+    Pong(PongMessage),
+    Text(TextMessage),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -32,6 +126,7 @@ pub struct BenchMessage {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ControllerMessage {
     pub time_ms: u64,
+    pub state: control::BalancingState,
     pub control: f32,
     pub sensor_pend_angle: f32,
     pub sensor_wheel_velocity: f32,
@@ -39,3 +134,141 @@ pub struct ControllerMessage {
     pub pend_velocity: f32,
     pub wheel_velocity: f32,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FaultMessage {
+    pub time_ms: u64,
+    pub fault: safety::Fault,
+}
+
+/// Snapshot of the estimator covariance `EKF::P`, indexed `p[row][column]`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CovarianceMessage {
+    pub time_ms: u64,
+    pub p: [[f32; 3]; 3],
+}
+
+/// Longest `TextMessage::text`, longer lines are cut short.
+pub const MAX_TEXT_LEN: usize = 96;
+
+/// A line of the firmware's log, for the events worth seeing without a debug probe.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TextMessage {
+    pub time_ms: u64,
+    pub level: LogLevel,
+    pub text: heapless::String<MAX_TEXT_LEN>,
+}
+
+impl TextMessage {
+    pub fn new(time_ms: u64, level: LogLevel, text: &str) -> Self {
+        TextMessage {
+            time_ms,
+            level,
+            text: truncated(text),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Reply to `CommandMessage::Ping`, from which the receiver relates the device clock,
+/// the one every `time_ms` is read from, to its own.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PongMessage {
+    /// Echoed from the ping.
+    pub host_time_us: u64,
+    /// Device time in microseconds when the ping arrived.
+    pub received_us: u64,
+    /// Device time in microseconds when the pong was written to the link.
+    pub sent_us: u64,
+}
+
+/// Control loop timing over the last `ticks` iterations.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimingMessage {
+    pub time_ms: u64,
+    pub ticks: u32,
+    /// Iterations since boot that finished after the next tick was due.
+    pub overruns: u32,
+    /// Delay between when a tick was due and when the loop woke up.
+    pub jitter: timing::Summary,
+    /// Time from wake-up to the end of the iteration.
+    pub compute: timing::Summary,
+    /// Time spent waiting for the encoder.
+    pub encoder: timing::Summary,
+}
+
+/// Reply to `CommandMessage::RequestStatus`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusMessage {
+    pub time_ms: u64,
+    pub mode: control::Mode,
+    pub state: control::BalancingState,
+    pub fault: Option<safety::Fault>,
+    pub gains: [f32; 3],
+    pub output: f32,
+}
+
+/// Sent for every received `CommandRequest` once it has been handled, except for pings,
+/// which are answered with a `PongMessage`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AckMessage {
+    pub id: u32,
+    pub result: Result<(), CommandError>,
+}
+
+/// Why a command was rejected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    /// `SetMotorOutput` is only accepted in bench mode.
+    NotInBenchMode,
+    /// An argument was NaN, infinite or out of range.
+    InvalidValue,
+}
+
+/// Commands from the receiver to the firmware.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CommandMessage {
+    /// Turns the motor off, the estimator keeps running.
+    StopMotor,
+    /// Clears any fault and starts swinging up.
+    StartSwingUp,
+    /// Turns the motor off and hands it over to `SetMotorOutput`.
+    EnterBenchMode,
+    /// Output in [-1, 1] to hold the motor at in bench mode.
+    SetMotorOutput(f32),
+    SetGains([f32; 3]),
+    /// Asks for a `StatusMessage`.
+    RequestStatus,
+    /// Switches how telemetry is sent for the rest of the connection.
+    SetTransport(Transport),
+    /// Asks for a `PongMessage`, answered by the network task without waiting for the
+    /// control loop.
+    Ping {
+        /// Host time in microseconds since the Unix epoch.
+        host_time_us: u64,
+    },
+}
+
+/// How the firmware streams telemetry to a client.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Over the TCP connection the client opened.
+    Tcp,
+    /// One datagram per batch to `port` at the client's address. Commands, and the
+    /// connection itself, stay on TCP.
+    Udp { port: u16 },
+}
+
+/// A command together with the id its `AckMessage` will carry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommandRequest {
+    pub id: u32,
+    pub command: CommandMessage,
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+use crate::model::WHEEL_STATIC_GAIN;
+
+/// Reason why the motor output was cut.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The controller requested a NaN or infinite output.
+    InvalidOutput,
+    /// The estimated wheel velocity exceeded `SafetyLimits::max_wheel_velocity`.
+    WheelOverSpeed,
+    /// The output stayed at or above `SafetyLimits::saturation_level` for longer than
+    /// `SafetyLimits::max_saturation_time`.
+    SaturationTimeout,
+    /// The previous run was reset by the watchdog.
+    WatchdogReset,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SafetyLimits {
+    /// Largest allowed change of the output per second.
+    pub max_slew_rate: f32,
+    /// Wheel velocity, in the same unit as the estimated `x[0]`, at which the motor is cut.
+    pub max_wheel_velocity: f32,
+    /// Output magnitude that counts as saturated.
+    pub saturation_level: f32,
+    /// Seconds the output may stay saturated before the motor is cut.
+    pub max_saturation_time: f32,
+}
+
+impl Default for SafetyLimits {
+    fn default() -> Self {
+        SafetyLimits {
+            max_slew_rate: 20.0,
+            max_wheel_velocity: 0.9 * WHEEL_STATIC_GAIN,
+            saturation_level: 0.9,
+            max_saturation_time: 2.0,
+        }
+    }
+}
+
+/// Sits between the controller and the motor and limits what is actually applied.
+///
+/// Once a fault has been detected the output is held at zero until `reset` is called.
+pub struct OutputGuard {
+    pub limits: SafetyLimits,
+    output: f32,
+    saturation_time: f32,
+    fault: Option<Fault>,
+}
+
+impl OutputGuard {
+    pub fn new(limits: SafetyLimits) -> Self {
+        OutputGuard {
+            limits,
+            output: 0.0,
+            saturation_time: 0.0,
+            fault: None,
+        }
+    }
+
+    /// Returns the output that should be applied to the motor given the `requested`
+    /// output, the estimated wheel velocity and the time `dt` since the last update.
+    pub fn update(&mut self, requested: f32, wheel_velocity: f32, dt: f32) -> f32 {
+        if self.fault.is_none() {
+            self.fault = self.check_inputs(requested, wheel_velocity);
+        }
+        if self.fault.is_none() {
+            let max_step = self.limits.max_slew_rate * dt;
+            let step = requested.clamp(-1.0, 1.0) - self.output;
+            self.output += step.clamp(-max_step, max_step);
+            self.fault = self.check_saturation(dt);
+        }
+        if self.fault.is_some() {
+            self.output = 0.0;
+        }
+        self.output
+    }
+
+    fn check_inputs(&self, requested: f32, wheel_velocity: f32) -> Option<Fault> {
+        if !requested.is_finite() {
+            return Some(Fault::InvalidOutput);
+        }
+        if wheel_velocity.abs() > self.limits.max_wheel_velocity {
+            return Some(Fault::WheelOverSpeed);
+        }
+        None
+    }
+
+    /// Accounts for `dt` at the output about to be applied.
+    fn check_saturation(&mut self, dt: f32) -> Option<Fault> {
+        if self.output.abs() >= self.limits.saturation_level {
+            self.saturation_time += dt;
+        } else {
+            self.saturation_time = 0.0;
+        }
+        if self.saturation_time > self.limits.max_saturation_time {
+            return Some(Fault::SaturationTimeout);
+        }
+        None
+    }
+
+    /// The output that was last returned by `update`.
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    /// Latches `fault` as if it had been detected by the guard itself.
+    pub fn trip(&mut self, fault: Fault) {
+        self.fault.get_or_insert(fault);
+        self.output = 0.0;
+    }
+
+    /// Clears a latched fault. The output restarts from zero.
+    pub fn reset(&mut self) {
+        self.fault = None;
+        self.output = 0.0;
+        self.saturation_time = 0.0;
+    }
+}
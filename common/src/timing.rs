@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+
+use crate::TimingMessage;
+
+/// Min, max and mean of a set of durations in microseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct Summary {
+    pub min_us: u32,
+    pub max_us: u32,
+    pub mean_us: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Stats {
+    min: u32,
+    max: u32,
+    sum: u64,
+    count: u32,
+}
+
+impl Stats {
+    const EMPTY: Stats = Stats {
+        min: u32::MAX,
+        max: 0,
+        sum: 0,
+        count: 0,
+    };
+
+    fn add(&mut self, value: u32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value as u64;
+        self.count += 1;
+    }
+
+    fn summary(&self) -> Summary {
+        if self.count == 0 {
+            return Summary::default();
+        }
+        Summary {
+            min_us: self.min,
+            max_us: self.max,
+            mean_us: (self.sum / self.count as u64) as u32,
+        }
+    }
+}
+
+/// Measures a periodic loop and summarises it every `window` iterations.
+///
+/// Call `start` right after waking up on the tick, `encoder_read` with the latency of
+/// the sensor read and `end` when the iteration is done.
+pub struct LoopTimer {
+    period_us: u64,
+    window: u32,
+    deadline_us: Option<u64>,
+    start_us: u64,
+    jitter: Stats,
+    compute: Stats,
+    encoder: Stats,
+    overruns: u32,
+}
+
+impl LoopTimer {
+    pub fn new(period_us: u64, window: u32) -> Self {
+        LoopTimer {
+            period_us,
+            window,
+            deadline_us: None,
+            start_us: 0,
+            jitter: Stats::EMPTY,
+            compute: Stats::EMPTY,
+            encoder: Stats::EMPTY,
+            overruns: 0,
+        }
+    }
+
+    pub fn start(&mut self, now_us: u64) {
+        let deadline = *self.deadline_us.get_or_insert(now_us);
+        self.jitter.add(now_us.saturating_sub(deadline) as u32);
+        self.deadline_us = Some(deadline + self.period_us);
+        self.start_us = now_us;
+    }
+
+    pub fn encoder_read(&mut self, latency_us: u64) {
+        self.encoder.add(latency_us as u32);
+    }
+
+    /// Returns a report when a full window has been collected.
+    pub fn end(&mut self, now_us: u64) -> Option<TimingMessage> {
+        self.compute
+            .add(now_us.saturating_sub(self.start_us) as u32);
+        if self.deadline_us.is_some_and(|deadline| now_us > deadline) {
+            self.overruns += 1;
+        }
+
+        if self.compute.count < self.window {
+            return None;
+        }
+        let report = TimingMessage {
+            time_ms: now_us / 1000,
+            ticks: self.compute.count,
+            overruns: self.overruns,
+            jitter: self.jitter.summary(),
+            compute: self.compute.summary(),
+            encoder: self.encoder.summary(),
+        };
+        self.jitter = Stats::EMPTY;
+        self.compute = Stats::EMPTY;
+        self.encoder = Stats::EMPTY;
+        Some(report)
+    }
+
+    /// Number of iterations that finished after the next tick was due, since creation.
+    pub fn overruns(&self) -> u32 {
+        self.overruns
+    }
+}
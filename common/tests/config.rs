@@ -0,0 +1,60 @@
+use common::config::{ConfigError, DeviceConfig, IpConfig, MAX_RECORD_LEN, SECTOR_LEN, VERSION};
+
+fn config(ip: IpConfig) -> DeviceConfig {
+    DeviceConfig {
+        ssid: "x".repeat(32).as_str().try_into().unwrap(),
+        passphrase: "y".repeat(63).as_str().try_into().unwrap(),
+        ap_passphrase: "w".repeat(64).as_str().try_into().unwrap(),
+        ip,
+        port: u16::MAX,
+        name: "z".repeat(32).as_str().try_into().unwrap(),
+    }
+}
+
+/// A flash sector holding `config`, erased after the record.
+fn sector(config: &DeviceConfig) -> Vec<u8> {
+    let mut sector = vec![0xFF; SECTOR_LEN];
+    config.encode(&mut sector).unwrap();
+    sector
+}
+
+#[test]
+fn round_trips_the_largest_config() {
+    for ip in [
+        IpConfig::Dhcp,
+        IpConfig::Static {
+            address: [255; 4],
+            prefix_len: 32,
+            gateway: Some([255; 4]),
+        },
+    ] {
+        let config = config(ip);
+        let mut record = [0; MAX_RECORD_LEN];
+        assert!(config.encode(&mut record).is_some());
+        assert_eq!(DeviceConfig::decode(&sector(&config)), Ok(config));
+    }
+}
+
+#[test]
+fn rejects_blank_foreign_and_corrupt_sectors() {
+    assert_eq!(
+        DeviceConfig::decode(&vec![0xFF; SECTOR_LEN]),
+        Err(ConfigError::Blank)
+    );
+    assert_eq!(
+        DeviceConfig::decode(&vec![0; SECTOR_LEN]),
+        Err(ConfigError::Magic)
+    );
+
+    let good = sector(&config(IpConfig::Dhcp));
+    let mut corrupt = good.clone();
+    corrupt[20] ^= 1;
+    assert_eq!(DeviceConfig::decode(&corrupt), Err(ConfigError::Crc));
+
+    let mut newer = good;
+    newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    assert_eq!(
+        DeviceConfig::decode(&newer),
+        Err(ConfigError::Version(VERSION + 1))
+    );
+}
@@ -0,0 +1,94 @@
+use common::config::AP_ADDRESS;
+use common::dhcp::{MAX_LEASES, MAX_REPLY_LEN, Server};
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const RELEASE: u8 = 7;
+
+/// A client message as a laptop would broadcast it, with `options` after the message
+/// type.
+fn message(client: u8, message_type: u8, options: &[u8]) -> Vec<u8> {
+    let mut msg = vec![0; 240];
+    msg[..4].copy_from_slice(&[1, 1, 6, 0]);
+    msg[4..8].copy_from_slice(&[0xDE, 0xAD, client, 0xEF]);
+    msg[28..34].copy_from_slice(&[2, 0, 0, 0, 0, client]);
+    msg[236..240].copy_from_slice(&[99, 130, 83, 99]);
+    msg.extend_from_slice(&[53, 1, message_type]);
+    msg.extend_from_slice(options);
+    msg.push(255);
+    msg
+}
+
+fn request(client: u8, address: [u8; 4]) -> Vec<u8> {
+    let mut options = vec![50, 4];
+    options.extend_from_slice(&address);
+    options.extend_from_slice(&[54, 4]);
+    options.extend_from_slice(&AP_ADDRESS);
+    message(client, REQUEST, &options)
+}
+
+/// The message type and offered address of a reply to `msg`.
+fn reply(server: &mut Server, msg: &[u8]) -> Option<(u8, [u8; 4])> {
+    let mut out = [0; MAX_REPLY_LEN];
+    let n = server.handle(msg, &mut out)?;
+    assert_eq!(out[0], 2);
+    assert_eq!(out[4..8], msg[4..8], "transaction id");
+    assert_eq!(out[28..34], msg[28..34], "client hardware address");
+    assert_eq!(out[240..243], [53, 1, out[242]]);
+    assert!(n <= MAX_REPLY_LEN);
+    Some((out[242], out[16..20].try_into().unwrap()))
+}
+
+#[test]
+fn leases_an_address_to_each_client() {
+    let mut server = Server::new();
+    let first = [192, 168, 4, 2];
+    assert_eq!(
+        reply(&mut server, &message(1, DISCOVER, &[])),
+        Some((OFFER, first))
+    );
+    assert_eq!(reply(&mut server, &request(1, first)), Some((ACK, first)));
+    // Asking again, as when renewing, gets the same address.
+    assert_eq!(reply(&mut server, &request(1, first)), Some((ACK, first)));
+
+    let second = [192, 168, 4, 3];
+    assert_eq!(
+        reply(&mut server, &message(2, DISCOVER, &[])),
+        Some((OFFER, second))
+    );
+    assert_eq!(reply(&mut server, &request(2, first)).unwrap().0, NAK);
+}
+
+#[test]
+fn reuses_released_and_old_leases() {
+    let mut server = Server::new();
+    reply(&mut server, &message(1, DISCOVER, &[]));
+    assert_eq!(reply(&mut server, &message(1, RELEASE, &[])), None);
+    assert_eq!(
+        reply(&mut server, &message(2, DISCOVER, &[])),
+        Some((OFFER, [192, 168, 4, 2]))
+    );
+
+    for client in 3..3 + MAX_LEASES as u8 {
+        let (kind, address) = reply(&mut server, &message(client, DISCOVER, &[])).unwrap();
+        assert_eq!(kind, OFFER);
+        assert!(address[3] >= 2 && address[3] < 2 + MAX_LEASES as u8);
+    }
+}
+
+#[test]
+fn ignores_other_traffic() {
+    let mut server = Server::new();
+    assert_eq!(reply(&mut server, &[0; 100]), None);
+
+    let mut reply_from_elsewhere = message(1, OFFER, &[]);
+    reply_from_elsewhere[0] = 2;
+    assert_eq!(reply(&mut server, &reply_from_elsewhere), None);
+
+    // Accepting another server's offer.
+    let other_server = message(1, REQUEST, &[54, 4, 10, 0, 0, 1]);
+    assert_eq!(reply(&mut server, &other_server), None);
+}
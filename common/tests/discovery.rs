@@ -0,0 +1,55 @@
+//! Announcements sent between two sockets on the loopback interface, standing in for
+//! the broadcast between the device and a receiver.
+
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use common::PROTOCOL_VERSION;
+use common::discovery::{Announcement, MAGIC, MAX_DATAGRAM_LEN};
+
+fn loopback_pair() -> (UdpSocket, UdpSocket) {
+    let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    device.connect(receiver.local_addr().unwrap()).unwrap();
+    (device, receiver)
+}
+
+#[test]
+fn announcement_arrives_over_loopback() {
+    let (device, receiver) = loopback_pair();
+    let sent = Announcement::new("pendulum", 1234);
+    let mut buf = [0; MAX_DATAGRAM_LEN];
+    let n = sent.encode(&mut buf).unwrap();
+    device.send(&buf[..n]).unwrap();
+
+    let mut buf = [0; 1500];
+    let (n, from) = receiver.recv_from(&mut buf).unwrap();
+    assert_eq!(from, device.local_addr().unwrap());
+    let received = Announcement::parse(&buf[..n]).unwrap().unwrap();
+    assert_eq!(received, sent);
+    assert_eq!(received.protocol_version, PROTOCOL_VERSION);
+}
+
+#[test]
+fn longest_name_fits_and_long_names_are_truncated() {
+    let announcement = Announcement::new(&"x".repeat(100), u16::MAX);
+    assert_eq!(announcement.name.len(), 32);
+    let mut buf = [0; MAX_DATAGRAM_LEN];
+    assert!(announcement.encode(&mut buf).is_some());
+}
+
+#[test]
+fn ignores_other_datagrams() {
+    assert!(Announcement::parse(b"").is_none());
+    assert!(Announcement::parse(b"M-SEARCH * HTTP/1.1").is_none());
+
+    let mut buf = [0; MAX_DATAGRAM_LEN];
+    let n = Announcement::new("pendulum", 1234)
+        .encode(&mut buf)
+        .unwrap();
+    assert!(Announcement::parse(&buf[..n - 1]).unwrap().is_err());
+    assert!(Announcement::parse(&MAGIC).unwrap().is_err());
+}
@@ -0,0 +1,180 @@
+//! Replays recorded runs through the estimator and compares the result against stored
+//! golden outputs, so any change that shifts the estimate on recorded data is noticed.
+//!
+//! A run is a CSV file in `tests/runs/` with a header and at least the `control` and
+//! `sensor_pend_angle` columns of `ControllerMessage`, one row per sample, and its device
+//! time as `time_ms` or `time_s`. `NaN` as angle means the encoder read failed. The
+//! `controller.csv` written by `receiver export --format csv` is such a file, as long as
+//! it holds a single connection. Samples missing from a run, like telemetry dropped on
+//! the link, are replayed as failed reads with the previous output.
+//!
+//! The expected estimates are stored next to the run in `<name>.golden.csv`. Set
+//! `UPDATE_GOLDEN=1` to rewrite them after an intended change, the test then fails so
+//! that it cannot pass with the variable set.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use common::SAMPLE_TIME_MS;
+use common::control::{Estimator, sub_angles};
+
+const GOLDEN_HEADER: &str = "wheel_velocity,pend_angle,pend_velocity";
+
+/// Columns of the golden output, in the order of `GOLDEN_HEADER`, with their tolerances.
+const TOLERANCES: [f32; 3] = [1e-3, 1e-5, 1e-4];
+
+struct Sample {
+    control: f32,
+    angle: Option<f32>,
+}
+
+fn read_run(path: &Path) -> Vec<Sample> {
+    let text = fs::read_to_string(path).unwrap();
+    let mut lines = text.lines();
+    let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|c| c.trim() == name)
+            .unwrap_or_else(|| panic!("{} has no {name} column", path.display()))
+    };
+    let has_column = |name: &str| header.iter().any(|c| c.trim() == name);
+    let (time, time_scale) = if has_column("time_ms") {
+        (column("time_ms"), 1.0)
+    } else {
+        (column("time_s"), 1000.0)
+    };
+    let control = column("control");
+    let angle = column("sensor_pend_angle");
+
+    // Telemetry sent as datagrams can arrive out of order.
+    let mut rows: Vec<(u64, Sample)> = lines
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let time_ms = fields[time].parse::<f64>().unwrap() * time_scale;
+            let angle: f32 = fields[angle].parse().unwrap_or(f32::NAN);
+            let sample = Sample {
+                control: fields[control].parse().unwrap(),
+                angle: (!angle.is_nan()).then_some(angle),
+            };
+            ((time_ms / SAMPLE_TIME_MS as f64).round() as u64, sample)
+        })
+        .collect();
+    rows.sort_by_key(|(tick, _)| *tick);
+
+    let mut samples: Vec<Sample> = Vec::new();
+    let mut last_tick: Option<u64> = None;
+    for (tick, sample) in rows {
+        if let Some(last_tick) = last_tick {
+            assert!(
+                tick > last_tick,
+                "{}: two samples at {} ms, keep one connection per run",
+                path.display(),
+                tick * SAMPLE_TIME_MS as u64
+            );
+            let held = samples.last().unwrap().control;
+            for _ in last_tick + 1..tick {
+                samples.push(Sample {
+                    control: held,
+                    angle: None,
+                });
+            }
+        }
+        last_tick = Some(tick);
+        samples.push(sample);
+    }
+    samples
+}
+
+/// Feeds the samples through the estimator like `ControlLoop::step` does, where the
+/// output logged on one tick is applied during the next.
+fn replay(samples: &[Sample]) -> Vec<[f32; 3]> {
+    let mut estimator = Estimator::new(SAMPLE_TIME_MS as f32 * 1e-3);
+    let mut prev_control = 0.0;
+    samples
+        .iter()
+        .map(|sample| {
+            estimator.update(prev_control, sample.angle);
+            prev_control = sample.control;
+            let x = estimator.x();
+            [x[0], x[1], x[2]]
+        })
+        .collect()
+}
+
+fn write_golden(path: &Path, estimates: &[[f32; 3]]) {
+    let mut text = format!("{GOLDEN_HEADER}\n");
+    for [wheel_velocity, angle, velocity] in estimates {
+        text += &format!("{wheel_velocity},{angle},{velocity}\n");
+    }
+    fs::write(path, text).unwrap();
+}
+
+fn read_golden(path: &Path) -> Vec<[f32; 3]> {
+    let text = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("{}: {e}, run with UPDATE_GOLDEN=1", path.display()));
+    let mut lines = text.lines();
+    assert_eq!(lines.next(), Some(GOLDEN_HEADER));
+    lines
+        .map(|line| {
+            let values: Vec<f32> = line.split(',').map(|v| v.parse().unwrap()).collect();
+            values.try_into().unwrap()
+        })
+        .collect()
+}
+
+fn runs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/runs");
+    let mut runs: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            name.ends_with(".csv") && !name.ends_with(".golden.csv")
+        })
+        .collect();
+    runs.sort();
+    runs
+}
+
+#[test]
+fn estimator_matches_golden_outputs() {
+    let runs = runs();
+    assert!(!runs.is_empty());
+
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    for run in &runs {
+        let golden_path = run.with_extension("golden.csv");
+        let estimates = replay(&read_run(run));
+        if update {
+            write_golden(&golden_path, &estimates);
+            continue;
+        }
+
+        let golden = read_golden(&golden_path);
+        assert_eq!(estimates.len(), golden.len(), "{}", run.display());
+        for (row, (estimate, expected)) in estimates.iter().zip(&golden).enumerate() {
+            for (i, name) in GOLDEN_HEADER.split(',').enumerate() {
+                let error = if name == "pend_angle" {
+                    sub_angles(estimate[i], expected[i])
+                } else {
+                    estimate[i] - expected[i]
+                };
+                assert!(
+                    error.abs() <= TOLERANCES[i] * expected[i].abs().max(1.0),
+                    "{} row {row}: {name} is {} but expected {}",
+                    run.display(),
+                    estimate[i],
+                    expected[i],
+                );
+            }
+        }
+    }
+    assert!(
+        !update,
+        "rewrote the golden outputs of {} runs, check them in and run again without \
+         UPDATE_GOLDEN",
+        runs.len()
+    );
+}
@@ -0,0 +1,57 @@
+use common::framing::{Decoder, FrameError, encode, max_frame_len};
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![0; max_frame_len(payload.len())];
+    let n = encode(payload, &mut out).unwrap();
+    out.truncate(n);
+    out
+}
+
+fn decode_all(stream: &[u8]) -> Vec<Result<Vec<u8>, FrameError>> {
+    let mut decoder = Decoder::<1024>::new();
+    stream
+        .iter()
+        .filter_map(|&b| decoder.push(b).map(|r| r.map(<[u8]>::to_vec)))
+        .collect()
+}
+
+#[test]
+fn round_trips_payloads() {
+    let long: Vec<u8> = (0..600).map(|i| (i % 7) as u8).collect();
+    let no_zeros = vec![0xAB; 300];
+    for payload in [&[][..], &[0], &[0, 0, 1], &long, &no_zeros] {
+        let encoded = frame(payload);
+        assert_eq!(encoded.iter().filter(|&&b| b == 0).count(), 1);
+        assert!(encoded.len() <= max_frame_len(payload.len()));
+        assert_eq!(decode_all(&encoded), vec![Ok(payload.to_vec())]);
+    }
+}
+
+#[test]
+fn skips_corrupt_frames_and_resynchronises() {
+    let mut stream = frame(b"first");
+    // Start in the middle of a frame, as after connecting to a running stream.
+    let partial = frame(b"lost");
+    stream.splice(0..0, partial[2..].iter().copied());
+    let mut corrupt = frame(b"corrupt");
+    corrupt[3] ^= 0x10;
+    stream.extend(corrupt);
+    stream.extend(frame(b"second"));
+    stream.extend(vec![0x55; 1100]);
+    stream.push(0);
+    stream.extend(frame(b"third"));
+
+    let decoded = decode_all(&stream);
+    // Depending on where it was cut, the partial frame fails either check.
+    assert!(decoded[0].is_err());
+    assert_eq!(
+        decoded[1..],
+        [
+            Ok(b"first".to_vec()),
+            Err(FrameError::Crc),
+            Ok(b"second".to_vec()),
+            Err(FrameError::Overflow),
+            Ok(b"third".to_vec()),
+        ]
+    );
+}
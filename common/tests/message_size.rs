@@ -0,0 +1,156 @@
+//! Checks that the largest possible instance of every message fits in
+//! `framing::MAX_MESSAGE_LEN`.
+
+use common::control::{BalancingState, Mode};
+use common::framing::{self, MAX_FRAME_LEN, MAX_MESSAGE_LEN};
+use common::safety::Fault;
+use common::timing::Summary;
+use common::*;
+use serde::Serialize;
+
+/// Every variant, with the values that take the most bytes to encode.
+fn largest_log_messages() -> Vec<LogMessage> {
+    let summary = Summary {
+        min_us: u32::MAX,
+        max_us: u32::MAX,
+        mean_us: u32::MAX,
+    };
+    vec![
+        LogMessage::Controller(ControllerMessage {
+            time_ms: u64::MAX,
+            state: BalancingState::Balancing,
+            control: f32::MAX,
+            sensor_pend_angle: f32::MAX,
+            sensor_wheel_velocity: f32::MAX,
+            pend_angle: f32::MAX,
+            pend_velocity: f32::MAX,
+            wheel_velocity: f32::MAX,
+        }),
+        LogMessage::Bench(BenchMessage {
+            time_ms: u64::MAX,
+            control: f32::MAX,
+            signed_rot_speed: f32::MAX,
+            abs_rot_speed: f32::MAX,
+        }),
+        LogMessage::Fault(FaultMessage {
+            time_ms: u64::MAX,
+            fault: Fault::WatchdogReset,
+        }),
+        LogMessage::Timing(TimingMessage {
+            time_ms: u64::MAX,
+            ticks: u32::MAX,
+            overruns: u32::MAX,
+            jitter: summary,
+            compute: summary,
+            encoder: summary,
+        }),
+        LogMessage::Alive,
+        LogMessage::Status(StatusMessage {
+            time_ms: u64::MAX,
+            mode: Mode::Bench(f32::MAX),
+            state: BalancingState::Balancing,
+            fault: Some(Fault::WatchdogReset),
+            gains: [f32::MAX; 3],
+            output: f32::MAX,
+        }),
+        LogMessage::Ack(AckMessage {
+            id: u32::MAX,
+            result: Err(CommandError::InvalidValue),
+        }),
+        LogMessage::Covariance(CovarianceMessage {
+            time_ms: u64::MAX,
+            p: [[f32::MAX; 3]; 3],
+        }),
+        LogMessage::Pong(PongMessage {
+            host_time_us: u64::MAX,
+            received_us: u64::MAX,
+            sent_us: u64::MAX,
+        }),
+        LogMessage::Text(TextMessage::new(
+            u64::MAX,
+            LogLevel::Error,
+            &"\u{10FFFF}".repeat(MAX_TEXT_LEN),
+        )),
+    ]
+}
+
+/// Fails to compile when a variant is added, as a reminder to add it above.
+fn variant_index(msg: &LogMessage) -> usize {
+    match msg {
+        LogMessage::Controller(_) => 0,
+        LogMessage::Bench(_) => 1,
+        LogMessage::Fault(_) => 2,
+        LogMessage::Timing(_) => 3,
+        LogMessage::Alive => 4,
+        LogMessage::Status(_) => 5,
+        LogMessage::Ack(_) => 6,
+        LogMessage::Covariance(_) => 7,
+        LogMessage::Pong(_) => 8,
+        LogMessage::Text(_) => 9,
+    }
+}
+
+fn assert_fits(name: &str, msg: &impl Serialize) {
+    let mut payload = [0_u8; MAX_MESSAGE_LEN];
+    let mut frame = [0_u8; MAX_FRAME_LEN];
+    assert!(
+        framing::encode_message(msg, &mut payload, &mut frame).is_some(),
+        "{name} does not fit in MAX_MESSAGE_LEN"
+    );
+}
+
+#[test]
+fn every_log_message_fits() {
+    let messages = largest_log_messages();
+    let mut indices: Vec<usize> = messages.iter().map(variant_index).collect();
+    indices.sort();
+    indices.dedup();
+    assert_eq!(indices.len(), messages.len(), "a variant is listed twice");
+    assert_eq!(
+        indices.last(),
+        Some(&(indices.len() - 1)),
+        "a variant is missing"
+    );
+
+    for msg in messages {
+        let name = format!("{msg:?}");
+        assert_fits(
+            &name,
+            &Envelope {
+                seq: u32::MAX,
+                dropped: u32::MAX,
+                msg,
+            },
+        );
+    }
+}
+
+#[test]
+fn hello_and_commands_fit() {
+    let mut hello = HelloMessage::new(&"x".repeat(100), &"x".repeat(100), [f32::MAX; 3]);
+    hello.protocol_version = u16::MAX;
+    hello.sample_time_ms = u32::MAX;
+    assert_fits("HelloMessage", &hello);
+
+    for command in [
+        CommandMessage::StopMotor,
+        CommandMessage::StartSwingUp,
+        CommandMessage::EnterBenchMode,
+        CommandMessage::SetMotorOutput(f32::MAX),
+        CommandMessage::SetGains([f32::MAX; 3]),
+        CommandMessage::RequestStatus,
+        CommandMessage::SetTransport(Transport::Udp { port: u16::MAX }),
+        CommandMessage::Ping {
+            host_time_us: u64::MAX,
+        },
+    ] {
+        let name = format!("{command:?}");
+        assert_fits(
+            &name,
+            &CommandRequest {
+                id: u32::MAX,
+                command,
+            },
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use common::safety::{Fault, OutputGuard, SafetyLimits};
+
+const LIMITS: SafetyLimits = SafetyLimits {
+    max_slew_rate: 20.0,
+    max_wheel_velocity: 100.0,
+    saturation_level: 0.9,
+    max_saturation_time: 2.0,
+};
+
+fn assert_close(actual: f32, expected: f32) {
+    assert!(
+        (actual - expected).abs() < 1e-6,
+        "{actual} is not {expected}"
+    );
+}
+
+#[test]
+fn slew_rate_limits_the_step_per_dt() {
+    let mut guard = OutputGuard::new(LIMITS);
+    assert_close(guard.update(1.0, 0.0, 0.01), 0.2);
+    assert_close(guard.update(1.0, 0.0, 0.01), 0.4);
+    // A longer tick allows a larger step.
+    assert_close(guard.update(1.0, 0.0, 0.02), 0.8);
+    assert_close(guard.update(-1.0, 0.0, 0.01), 0.6);
+    // Small changes go through as requested, requests beyond 1 are clamped.
+    assert_close(guard.update(0.55, 0.0, 0.01), 0.55);
+    assert_close(guard.update(5.0, 0.0, 1.0), 1.0);
+    assert_eq!(guard.fault(), None);
+}
+
+#[test]
+fn wheel_over_speed_cuts_the_motor() {
+    let mut guard = OutputGuard::new(LIMITS);
+    guard.update(0.5, 0.0, 0.1);
+    assert_close(guard.update(0.5, LIMITS.max_wheel_velocity, 0.1), 0.5);
+    assert_eq!(guard.fault(), None);
+
+    assert_eq!(guard.update(0.5, -100.5, 0.1), 0.0);
+    assert_eq!(guard.fault(), Some(Fault::WheelOverSpeed));
+}
+
+#[test]
+fn invalid_requests_cut_the_motor() {
+    for requested in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+        let mut guard = OutputGuard::new(LIMITS);
+        guard.update(0.5, 0.0, 0.1);
+        assert_eq!(guard.update(requested, 0.0, 0.1), 0.0);
+        assert_eq!(guard.fault(), Some(Fault::InvalidOutput));
+    }
+}
+
+#[test]
+fn saturation_times_out_counting_the_applied_output() {
+    // Ticks of a quarter second add up exactly, and the first one already reaches
+    // full output.
+    let dt = 0.25;
+    let mut guard = OutputGuard::new(LIMITS);
+    for _ in 0..8 {
+        assert_eq!(guard.update(1.0, 0.0, dt), 1.0);
+    }
+    assert_eq!(guard.fault(), None);
+    assert_eq!(guard.update(1.0, 0.0, dt), 0.0);
+    assert_eq!(guard.fault(), Some(Fault::SaturationTimeout));
+}
+
+#[test]
+fn dropping_below_saturation_restarts_the_timeout() {
+    let dt = 0.25;
+    let mut guard = OutputGuard::new(LIMITS);
+    for _ in 0..8 {
+        guard.update(1.0, 0.0, dt);
+    }
+    guard.update(0.5, 0.0, dt);
+    for _ in 0..8 {
+        guard.update(1.0, 0.0, dt);
+    }
+    assert_eq!(guard.fault(), None);
+}
+
+#[test]
+fn faults_latch_until_reset() {
+    let mut guard = OutputGuard::new(LIMITS);
+    guard.update(f32::NAN, 0.0, 0.01);
+    for _ in 0..10 {
+        assert_eq!(guard.update(0.5, 0.0, 0.01), 0.0);
+    }
+    assert_eq!(guard.fault(), Some(Fault::InvalidOutput));
+
+    guard.reset();
+    assert_eq!(guard.fault(), None);
+    // The output restarts from zero under the slew limit.
+    assert_close(guard.update(1.0, 0.0, 0.01), 0.2);
+}
+
+#[test]
+fn trip_latches_the_first_fault() {
+    let mut guard = OutputGuard::new(LIMITS);
+    guard.update(1.0, 0.0, 0.1);
+    guard.trip(Fault::SaturationTimeout);
+    assert_eq!(guard.output(), 0.0);
+    assert_eq!(guard.update(1.0, 0.0, 0.1), 0.0);
+
+    guard.trip(Fault::WheelOverSpeed);
+    guard.update(f32::NAN, 1000.0, 0.1);
+    assert_eq!(guard.fault(), Some(Fault::SaturationTimeout));
+
+    guard.reset();
+    assert_eq!(guard.fault(), None);
+}
@@ -0,0 +1,87 @@
+use common::TimingMessage;
+use common::timing::LoopTimer;
+
+const PERIOD_US: u64 = 10_000;
+const WINDOW: u32 = 4;
+
+/// One iteration woken at `start_us`, reading the encoder in `encoder_us` and finishing
+/// at `end_us`.
+fn tick(
+    timer: &mut LoopTimer,
+    start_us: u64,
+    encoder_us: u64,
+    end_us: u64,
+) -> Option<TimingMessage> {
+    timer.start(start_us);
+    timer.encoder_read(encoder_us);
+    timer.end(end_us)
+}
+
+#[test]
+fn summarises_each_window() {
+    let mut timer = LoopTimer::new(PERIOD_US, WINDOW);
+    assert!(tick(&mut timer, 0, 50, 1_000).is_none());
+    assert!(tick(&mut timer, 10_100, 60, 12_100).is_none());
+    assert!(tick(&mut timer, 20_300, 70, 23_300).is_none());
+    // Finishes after the tick at 40 ms was due.
+    let report = tick(&mut timer, 30_200, 80, 42_200).unwrap();
+
+    assert_eq!(report.time_ms, 42);
+    assert_eq!(report.ticks, WINDOW);
+    assert_eq!(report.overruns, 1);
+    assert_eq!(
+        (
+            report.jitter.min_us,
+            report.jitter.max_us,
+            report.jitter.mean_us
+        ),
+        (0, 300, 150)
+    );
+    assert_eq!(
+        (
+            report.compute.min_us,
+            report.compute.max_us,
+            report.compute.mean_us
+        ),
+        (1_000, 12_000, 4_500)
+    );
+    assert_eq!(
+        (
+            report.encoder.min_us,
+            report.encoder.max_us,
+            report.encoder.mean_us
+        ),
+        (50, 80, 65)
+    );
+}
+
+#[test]
+fn reports_every_window_ticks_and_counts_overruns_since_creation() {
+    let mut timer = LoopTimer::new(PERIOD_US, WINDOW);
+    let mut reports = Vec::new();
+    for i in 0..3 * WINDOW as u64 {
+        let start_us = i * PERIOD_US;
+        // Every fifth iteration runs into the next one.
+        let compute_us = if i % 5 == 4 { PERIOD_US + 1 } else { 500 };
+        if let Some(report) = tick(&mut timer, start_us, 10, start_us + compute_us) {
+            reports.push((i, report));
+        }
+    }
+
+    let at: Vec<u64> = reports.iter().map(|(i, _)| *i).collect();
+    assert_eq!(at, [3, 7, 11]);
+    let overruns: Vec<u32> = reports.iter().map(|(_, r)| r.overruns).collect();
+    assert_eq!(overruns, [0, 1, 2]);
+    assert_eq!(timer.overruns(), 2);
+    for (_, report) in &reports {
+        assert_eq!(report.ticks, WINDOW);
+        assert_eq!(report.jitter.max_us, 0);
+    }
+}
+
+#[test]
+fn ending_exactly_on_the_next_tick_is_not_an_overrun() {
+    let mut timer = LoopTimer::new(PERIOD_US, 1);
+    let report = tick(&mut timer, 0, 10, PERIOD_US).unwrap();
+    assert_eq!(report.overruns, 0);
+}
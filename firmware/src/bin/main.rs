@@ -3,48 +3,36 @@
 
 use core::f32::consts::PI;
 
-use common::filter::{EKF, Mat, NLModel, RADIUS};
-use cyw43::Control;
+use common::SAMPLE_TIME_MS;
+use common::config::{DEFAULT_NAME, DEFAULT_PORT, IpConfig};
+use common::control::{BalancingState, ControlLoop, DEFAULT_GAINS, Mode, sub_angles};
+use common::safety::Fault;
+use common::{AckMessage, BenchMessage, CommandMessage, FaultMessage, HelloMessage, LogMessage};
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_rp::gpio::{Level, Output};
 use embassy_rp::i2c::I2c;
 use embassy_rp::pwm::Pwm;
-use embassy_time::{Duration, Ticker, Timer};
+use embassy_rp::watchdog::{ResetReason, Watchdog};
+use embassy_time::{Duration, Instant};
 use firmware::Netresources;
-use firmware::encoder::{MagneticEncoder, RotaryEncoder};
+use firmware::clock::TickerClock;
+use firmware::encoder::MagneticEncoder;
+use firmware::log;
 use firmware::motor::NidecMotor;
-use firmware::server::start_network;
+use firmware::server::{
+    CommandQueue, GAINS, GOT_CONNECTION, announcer, start_network, transmitter,
+};
+use firmware::telemetry::Telemetry;
 
 use {defmt_rtt as _, panic_probe as _};
 
-fn sub_angles(a: f32, b: f32) -> f32 {
-    let mut diff_angle = a - b;
-    while diff_angle < -core::f32::consts::PI {
-        diff_angle += 2.0 * core::f32::consts::PI;
-    }
-    while diff_angle > core::f32::consts::PI {
-        diff_angle -= 2.0 * core::f32::consts::PI;
-    }
-    diff_angle
-}
+static TELEMETRY: Telemetry = Telemetry::new();
 
-#[derive(PartialEq, Clone, Copy)]
-enum BalancingState {
-    Swinging,
-    Chilling,
-    Balancing,
-}
+static COMMANDS: CommandQueue = CommandQueue::new();
 
-#[embassy_executor::task]
-async fn blinker(mut led: Control<'static>) {
-    loop {
-        led.gpio_set(0, true).await;
-        Timer::after_millis(500).await;
-        led.gpio_set(0, false).await;
-        Timer::after_millis(500).await;
-    }
-}
+/// The board is reset if the control loop has not run for this many milliseconds.
+const WATCHDOG_TIMEOUT_MS: u64 = 100;
 
 #[embassy_executor::main]
 pub async fn entrypoint(spawner: Spawner) {
@@ -55,6 +43,12 @@ pub async fn entrypoint(spawner: Spawner) {
     let mut motor = NidecMotor::new(dir_pin, motor_pwm);
     motor.set_output(0.0);
 
+    let mut watchdog = Watchdog::new(p.WATCHDOG);
+    let watchdog_reset = matches!(watchdog.reset_reason(), Some(ResetReason::TimedOut));
+    if watchdog_reset {
+        warn!("Rebooted by the watchdog, motor disabled until reset");
+    }
+
     // Initialize network server.
     let r = Netresources {
         pwr: p.PIN_23,
@@ -64,107 +58,154 @@ pub async fn entrypoint(spawner: Spawner) {
         spi_clk: p.PIN_29,
         dma: p.DMA_CH0,
     };
-    let (_stack, control) = start_network(r, &spawner).await;
-    unwrap!(spawner.spawn(blinker(control)));
+    let config = firmware::config::load()
+        .inspect_err(|e| {
+            warn!(
+                "No network config ({}), write one with `receiver write-config`",
+                Debug2Format(e)
+            )
+        })
+        .ok();
+    let ip = config.as_ref().map_or(IpConfig::Dhcp, |config| config.ip);
+    let (stack, control) = start_network(r, ip, &spawner).await;
+    let (name, port) = match &config {
+        Some(config) => (config.name.clone(), config.port),
+        None => (unwrap!(DEFAULT_NAME.try_into()), DEFAULT_PORT),
+    };
+    unwrap!(spawner.spawn(announcer(stack, name, port)));
+    let hello = HelloMessage::new(env!("CARGO_BIN_NAME"), env!("BUILD_ID"), DEFAULT_GAINS);
+    unwrap!(spawner.spawn(transmitter(
+        stack, control, config, hello, &TELEMETRY, &COMMANDS
+    )));
 
     let sda = p.PIN_0;
     let scl = p.PIN_1;
     let i2c = I2c::new_async(p.I2C0, scl, sda, firmware::Irqs, Default::default());
-    let mut encoder = MagneticEncoder { channel: i2c };
+    let encoder = MagneticEncoder { channel: i2c };
     let bottom_angle = 0.7473148 + 0.035;
     info!("bottom_angle = {}", bottom_angle);
     let ref_angle = sub_angles(bottom_angle, PI);
 
-    let mut ticker = Ticker::every(Duration::from_millis(10));
-    let mut ekf = EKF::from_model(NLModel { dt: 0.01 });
-    ekf.x[1] = PI;
+    let clock = TickerClock::new(Duration::from_millis(SAMPLE_TIME_MS as u64));
+    let mut ctl = ControlLoop::new(motor, encoder, clock, ref_angle);
+    if watchdog_reset {
+        ctl.guard.trip(Fault::WatchdogReset);
+    }
 
-    let mut prev_state = BalancingState::Swinging;
-    let mut state = BalancingState::Swinging;
+    let mut prev_state = ctl.controller.state;
+    let mut reported_overruns = 0;
+    let mut reported_dropped = 0;
 
     info!("Entering loop...");
 
+    watchdog.pause_on_debug(true);
+    watchdog.start(Duration::from_millis(WATCHDOG_TIMEOUT_MS));
+
     loop {
-        ticker.next().await;
+        let step = ctl.step().await;
+        watchdog.feed();
+
+        // Every sample, so for the probe only.
+        if ctl.mode == Mode::Balancing && prev_state == BalancingState::Balancing {
+            let f = ctl.controller.gains;
+            let msg = &step.message;
+            info!(
+                "{} {} {}",
+                f[0] * msg.wheel_velocity,
+                f[1] * msg.pend_angle,
+                f[2] * msg.pend_velocity
+            );
+        }
 
-        ekf.time_update(motor.output);
+        let time_ms = step.message.time_ms;
+        if let Mode::Bench(_) = ctl.mode {
+            // There is no wheel speed sensor, the speed is the estimator's.
+            let msg = &step.message;
+            TELEMETRY.publish(LogMessage::Bench(BenchMessage {
+                time_ms,
+                control: msg.control,
+                signed_rot_speed: msg.wheel_velocity,
+                abs_rot_speed: msg.wheel_velocity.abs(),
+            }));
+        } else {
+            TELEMETRY.publish(LogMessage::Controller(step.message));
+        }
 
-        if let Ok(raw_angle) = encoder.rotation().await {
-            let angle = sub_angles(raw_angle, ref_angle);
-            let pred = ekf.x[1];
-            ekf.measurment_update_from_error([sub_angles(angle, pred)].into());
+        if let Some(fault) = step.new_fault {
+            error!("Motor cut: {}", Debug2Format(&fault));
+            TELEMETRY.publish(LogMessage::Fault(FaultMessage { time_ms, fault }));
         }
 
-        while ekf.x[1] < -PI {
-            ekf.x[1] += 2.0 * PI
+        if let Some(report) = step.timing {
+            debug!(
+                "loop: jitter max {}us, compute mean {}us max {}us, encoder max {}us",
+                report.jitter.max_us,
+                report.compute.mean_us,
+                report.compute.max_us,
+                report.encoder.max_us,
+            );
+            if report.overruns > reported_overruns {
+                log!(
+                    &TELEMETRY,
+                    Warn,
+                    "{} control loop overruns",
+                    report.overruns
+                );
+                reported_overruns = report.overruns;
+            }
+            TELEMETRY.publish(LogMessage::Timing(report));
+
+            let dropped = TELEMETRY.dropped();
+            if dropped > reported_dropped && TELEMETRY.clients() > 0 {
+                log!(
+                    &TELEMETRY,
+                    Warn,
+                    "{} telemetry messages dropped",
+                    dropped - reported_dropped
+                );
+            }
+            reported_dropped = dropped;
         }
-        while ekf.x[1] > PI {
-            ekf.x[1] -= 2.0 * PI
+
+        if let Some(covariance) = step.covariance {
+            TELEMETRY.publish(LogMessage::Covariance(covariance));
         }
 
-        let f: Mat<1, 3> = [[-0.00582551], [-8.00347], [-0.967164]].into();
-
-        state = match state {
-            BalancingState::Swinging => {
-                let top_energy = RADIUS * 9.81;
-                let cur_energy = RADIUS * 9.81 * libm::cosf(ekf.x[1])
-                    + RADIUS * ekf.x[2] * RADIUS * ekf.x[2] / 2.0;
-
-                if ekf.x[0].abs() > 330.0 * 0.2 {
-                    motor.set_output(ekf.x[2].signum() * 0.15);
-                } else if cur_energy < top_energy {
-                    motor.set_output(-ekf.x[2].signum() * 0.2);
-                } else if cur_energy > top_energy {
-                    motor.set_output(ekf.x[2].signum() * 0.2);
-                } else {
-                    motor.set_output(ekf.x[0] / 330.0);
-                }
-
-                let u = (-f * ekf.x)[0];
-                if sub_angles(ekf.x[1], 0.0).abs() < 0.2 && u.abs() < 3.0 {
-                    BalancingState::Balancing
-                } else {
-                    BalancingState::Swinging
-                }
+        // A fault that latched before a client connected is news to it.
+        while let Some(client) = GOT_CONNECTION.dequeue() {
+            if let Some(fault) = ctl.guard.fault() {
+                let time_ms = Instant::now().as_millis();
+                TELEMETRY.publish_to(client, LogMessage::Fault(FaultMessage { time_ms, fault }));
             }
-            BalancingState::Chilling => {
-                let top_energy = RADIUS * 9.81;
-                let bot_energy = -RADIUS * 9.81;
-                let cur_energy = RADIUS * 9.81 * libm::cosf(ekf.x[1])
-                    + RADIUS * ekf.x[2] * RADIUS * ekf.x[2] / 2.0;
-
-                let boundary = 0.7;
-                if cur_energy > boundary * top_energy + (1.0 - boundary) * bot_energy {
-                    motor.set_output(ekf.x[2].signum() * 0.3);
-                    BalancingState::Chilling
-                } else {
-                    BalancingState::Swinging
-                }
+        }
+
+        while let Some((client, request)) = COMMANDS.dequeue() {
+            let result = ctl.apply(&request.command);
+            // Also tells the other clients what was done.
+            match result {
+                Ok(()) => log!(&TELEMETRY, Info, "Command: {:?}", request.command),
+                Err(e) => log!(&TELEMETRY, Warn, "Rejected {:?}: {:?}", request.command, e),
             }
-            BalancingState::Balancing => {
-                let f: Mat<1, 3> = [[-0.00582551], [-8.00347], [-0.967164]].into();
-                let u = (-f * ekf.x)[0];
-                info!(
-                    "{} {} {}",
-                    f[0] * ekf.x[0],
-                    f[1] * ekf.x[1],
-                    f[2] * ekf.x[2]
-                );
-                motor.set_output(u.clamp(-1.0, 1.0));
-                if sub_angles(ekf.x[1], 0.0).abs() > 0.25 {
-                    BalancingState::Chilling
-                } else {
-                    BalancingState::Balancing
-                }
+            if request.command == CommandMessage::RequestStatus {
+                TELEMETRY.publish_to(client, LogMessage::Status(ctl.status()));
             }
-        };
-        if prev_state != state {
-            match state {
-                BalancingState::Swinging => info!("Swinging"),
-                BalancingState::Chilling => info!("Chilling"),
-                BalancingState::Balancing => info!("Balancing"),
+            TELEMETRY.publish_to(
+                client,
+                LogMessage::Ack(AckMessage {
+                    id: request.id,
+                    result,
+                }),
+            );
+            GAINS.store(ctl.controller.gains);
+        }
+
+        if prev_state != ctl.controller.state {
+            match ctl.controller.state {
+                BalancingState::Swinging => log!(&TELEMETRY, Info, "Swinging"),
+                BalancingState::Chilling => log!(&TELEMETRY, Info, "Chilling"),
+                BalancingState::Balancing => log!(&TELEMETRY, Info, "Balancing"),
             }
         }
-        prev_state = state;
+        prev_state = ctl.controller.state;
     }
 }
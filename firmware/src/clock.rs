@@ -0,0 +1,25 @@
+pub use common::hal::Clock;
+use embassy_time::{Duration, Instant, Ticker};
+
+/// Ticks every `period` using the embassy time driver.
+pub struct TickerClock {
+    ticker: Ticker,
+}
+
+impl TickerClock {
+    pub fn new(period: Duration) -> Self {
+        TickerClock {
+            ticker: Ticker::every(period),
+        }
+    }
+}
+
+impl Clock for TickerClock {
+    async fn tick(&mut self) {
+        self.ticker.next().await;
+    }
+
+    fn now_us(&self) -> u64 {
+        Instant::now().as_micros()
+    }
+}
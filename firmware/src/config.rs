@@ -0,0 +1,10 @@
+//! The network settings in their own flash sector, see `common::config`. Written with
+//! `receiver write-config`.
+
+use common::config::{ConfigError, DeviceConfig, FLASH_ADDRESS, MAX_RECORD_LEN};
+
+pub fn load() -> Result<DeviceConfig, ConfigError> {
+    // The sector is read through the flash's memory mapping, like the CYW43 firmware.
+    let record = unsafe { core::slice::from_raw_parts(FLASH_ADDRESS as *const u8, MAX_RECORD_LEN) };
+    DeviceConfig::decode(record)
+}
@@ -0,0 +1,43 @@
+//! Hands out addresses to the laptops on the device's access point, see
+//! `common::dhcp`.
+
+use common::dhcp::{CLIENT_PORT, MAX_REPLY_LEN, SERVER_PORT, Server};
+use defmt::*;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint};
+
+pub async fn serve(stack: embassy_net::Stack<'_>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 2 * MAX_REPLY_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    unwrap!(socket.bind(SERVER_PORT));
+
+    let mut server = Server::new();
+    let mut request = [0; 576];
+    let mut reply = [0; MAX_REPLY_LEN];
+    // Clients have no address yet, so every reply is broadcast.
+    let clients = IpEndpoint::new(IpAddress::v4(255, 255, 255, 255), CLIENT_PORT);
+    loop {
+        let n = match socket.recv_from(&mut request).await {
+            Ok((n, _)) => n,
+            Err(e) => {
+                warn!("dhcp receive error: {:?}", e);
+                continue;
+            }
+        };
+        let Some(len) = server.handle(&request[..n], &mut reply) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&reply[..len], clients).await {
+            warn!("dhcp send error: {:?}", e);
+        }
+    }
+}
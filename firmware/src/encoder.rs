@@ -1,10 +1,4 @@
-pub trait RotaryEncoder {
-    type Error;
-
-    /// Returns rotation in radians
-    #[allow(async_fn_in_trait)]
-    async fn rotation(&mut self) -> Result<f32, Self::Error>;
-}
+pub use common::hal::RotaryEncoder;
 
 pub struct MagneticEncoder<I> {
     pub channel: I,
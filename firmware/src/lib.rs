@@ -34,6 +34,11 @@ bind_interrupts!(pub struct Irqs {
     PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
 });
 
+pub mod clock;
+pub mod config;
+pub mod dhcp;
 pub mod encoder;
+pub mod log;
 pub mod motor;
 pub mod server;
+pub mod telemetry;
@@ -0,0 +1,52 @@
+//! Log lines that go to defmt and, as `LogMessage::Text`, to every connected client, so
+//! they can be followed without a debug probe. Meant for events, not for every sample.
+
+use core::fmt::{self, Write};
+
+use common::{LogMessage, MAX_TEXT_LEN, TextMessage};
+use embassy_time::Instant;
+use heapless::String;
+
+use crate::telemetry::Telemetry;
+
+pub use common::LogLevel;
+
+/// Logs a line formatted like `format_args!` at a `LogLevel`, for example
+/// `log!(&TELEMETRY, Info, "{} overruns", n)`.
+#[macro_export]
+macro_rules! log {
+    ($telemetry:expr, $level:ident, $($arg:tt)+) => {
+        $crate::log::log($telemetry, $crate::log::LogLevel::$level, format_args!($($arg)+))
+    };
+}
+
+/// Keeps what fits and drops the rest rather than failing.
+struct Truncating(String<MAX_TEXT_LEN>);
+
+impl Write for Truncating {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn log(telemetry: &Telemetry, level: LogLevel, args: fmt::Arguments) {
+    let mut text = Truncating(String::new());
+    let _ = text.write_fmt(args);
+    let text = text.0;
+    match level {
+        LogLevel::Debug => defmt::debug!("{=str}", text.as_str()),
+        LogLevel::Info => defmt::info!("{=str}", text.as_str()),
+        LogLevel::Warn => defmt::warn!("{=str}", text.as_str()),
+        LogLevel::Error => defmt::error!("{=str}", text.as_str()),
+    }
+    telemetry.publish(LogMessage::Text(TextMessage {
+        time_ms: Instant::now().as_millis(),
+        level,
+        text,
+    }));
+}
@@ -1,3 +1,4 @@
+pub use common::hal::Motor;
 use embassy_rp::{
     gpio::Output,
     pwm::{self, Pwm, SetDutyCycle},
@@ -41,3 +42,13 @@ impl NidecMotor {
         }
     }
 }
+
+impl Motor for NidecMotor {
+    fn set_output(&mut self, output: f32) {
+        NidecMotor::set_output(self, output);
+    }
+
+    fn output(&self) -> f32 {
+        self.output
+    }
+}
@@ -1,23 +1,92 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::telemetry::{ClientId, MAX_CLIENTS, Subscription, Telemetry};
 use crate::{Irqs, Netresources};
-use common::LogMessage;
+use common::config::{
+    AP_ADDRESS, AP_CHANNEL, AP_PREFIX_LEN, AP_SSID, DEFAULT_AP_PASSPHRASE, DEFAULT_PORT,
+    DeviceConfig, IpConfig,
+};
+use common::control::DEFAULT_GAINS;
+use common::discovery::{self, Announcement};
+use common::{
+    AckMessage, CommandMessage, CommandRequest, HelloMessage, LogMessage, PongMessage, Transport,
+    framing,
+};
 use cyw43::{Control, JoinOptions};
 use cyw43_pio::PioSpi;
 use defmt::*;
 use embassy_executor::Spawner;
-use embassy_net::StackResources;
-use embassy_net::tcp::TcpSocket;
+use embassy_futures::join::{join, join_array};
+use embassy_futures::select::{Either, select};
+use embassy_net::tcp::{TcpReader, TcpSocket, TcpWriter};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{
+    ConfigV4, IpAddress, IpEndpoint, Ipv4Address, Ipv4Cidr, StackResources, StaticConfigV4,
+};
 use embassy_rp::clocks::RoscRng;
 use embassy_rp::gpio::{Level, Output};
 use embassy_rp::interrupt::typelevel::Interrupt;
 use embassy_rp::peripherals::{DMA_CH0, PIO0};
 use embassy_rp::pio::Pio;
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::Write;
 use heapless::mpmc;
 use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
-pub static GOT_CONNECTION: mpmc::Q4<()> = mpmc::Q4::new();
+/// Clients that were just greeted, for the control loop to catch up on what they missed.
+pub static GOT_CONNECTION: mpmc::Q4<ClientId> = mpmc::Q4::new();
+
+/// Gains the controller runs with, which each client is greeted with when it connects.
+pub static GAINS: Gains = Gains::new(DEFAULT_GAINS);
+
+/// Balancing gains shared between the control loop, which sets them, and the server.
+/// Both run on the same executor, so a reader never sees half of an update.
+pub struct Gains([AtomicU32; 3]);
+
+impl Gains {
+    pub const fn new(gains: [f32; 3]) -> Self {
+        Gains([
+            AtomicU32::new(gains[0].to_bits()),
+            AtomicU32::new(gains[1].to_bits()),
+            AtomicU32::new(gains[2].to_bits()),
+        ])
+    }
+
+    pub fn store(&self, gains: [f32; 3]) {
+        for (k, gain) in self.0.iter().zip(gains) {
+            k.store(gain.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn load(&self) -> [f32; 3] {
+        self.0
+            .each_ref()
+            .map(|k| f32::from_bits(k.load(Ordering::Relaxed)))
+    }
+}
+
+/// Commands received from the clients, waiting for the control loop. Replies go back to
+/// the client given with each command.
+pub type CommandQueue = mpmc::Q8<(ClientId, CommandRequest)>;
+
+/// Failed attempts to join the configured network before starting the access point.
+const JOIN_ATTEMPTS: u32 = 5;
+
+/// Most bytes sent in one write, small enough to fit in a single datagram.
+const BATCH_SIZE: usize = 1024;
+
+/// Time between writes. Everything queued in the meantime goes out in one batch.
+const BATCH_INTERVAL_MS: u64 = 20;
+
+/// How long a client may leave data or keepalives unacknowledged before it is dropped,
+/// freeing its slot for a new connection.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Quiet time after which a keepalive is sent, so that a client that vanished without
+/// closing the connection is noticed even while telemetry goes out over UDP.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(3);
 
 #[embassy_executor::task]
 async fn cyw43_task(
@@ -33,6 +102,7 @@ async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'sta
 
 pub async fn start_network(
     net: Netresources,
+    ip: IpConfig,
     spawner: &Spawner,
 ) -> (embassy_net::Stack<'static>, Control<'static>) {
     let pwr = Output::new(net.pwr, Level::Low);
@@ -67,8 +137,21 @@ pub async fn start_network(
     let mut rng = RoscRng;
     let seed = rng.next_u64();
 
-    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
-    let network_config = embassy_net::Config::dhcpv4(Default::default());
+    // DHCP, DNS, a TCP socket per client, the UDP telemetry socket, the announcements and
+    // the DHCP server of the access point.
+    static RESOURCES: StaticCell<StackResources<{ 5 + MAX_CLIENTS }>> = StaticCell::new();
+    let network_config = match ip {
+        IpConfig::Dhcp => embassy_net::Config::dhcpv4(Default::default()),
+        IpConfig::Static {
+            address: [a, b, c, d],
+            prefix_len,
+            gateway,
+        } => embassy_net::Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), prefix_len),
+            gateway: gateway.map(|[a, b, c, d]| Ipv4Address::new(a, b, c, d)),
+            dns_servers: Default::default(),
+        }),
+    };
     let (stack, runner) = embassy_net::new(
         net_device,
         network_config,
@@ -81,28 +164,69 @@ pub async fn start_network(
     (stack, control)
 }
 
+/// Broadcasts an `Announcement` of the server on `port` once the network is up, so
+/// receivers can find it without knowing its address.
+#[embassy_executor::task]
+pub async fn announcer(stack: embassy_net::Stack<'static>, name: heapless::String<32>, port: u16) {
+    let mut datagram = [0_u8; discovery::MAX_DATAGRAM_LEN];
+    let Some(n) = Announcement::new(&name, port).encode(&mut datagram) else {
+        warn!("Failed to encode announcement");
+        return;
+    };
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; discovery::MAX_DATAGRAM_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    unwrap!(socket.bind(discovery::PORT));
+
+    stack.wait_config_up().await;
+    let broadcast = IpEndpoint::new(IpAddress::v4(255, 255, 255, 255), discovery::PORT);
+    loop {
+        if let Err(e) = socket.send_to(&datagram[..n], broadcast).await {
+            warn!("announce error: {:?}", e);
+        }
+        Timer::after_millis(discovery::ANNOUNCE_INTERVAL_MS).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn blinker(mut led: Control<'static>) {
+    loop {
+        led.gpio_set(0, true).await;
+        Timer::after_millis(500).await;
+        led.gpio_set(0, false).await;
+        Timer::after_millis(500).await;
+    }
+}
+
+/// Joins the network in `config`, or opens an access point if that fails or there is
+/// no config, and serves clients there. See `serve`.
 #[embassy_executor::task]
 pub async fn transmitter(
     stack: embassy_net::Stack<'static>,
     mut control: Control<'static>,
-    channel: &'static mpmc::Q4<LogMessage>,
+    config: Option<DeviceConfig>,
+    hello: HelloMessage,
+    telemetry: &'static Telemetry,
+    commands: &'static CommandQueue,
 ) {
-    loop {
-        match control
-            .join(
-                "Berntsson2.4GHz",
-                JoinOptions::new("5826hej5826".as_bytes()),
-            )
-            .await
-        {
-            Ok(_) => {
-                info!("Joined network!");
-                break;
-            }
-            Err(err) => {
-                info!("join failed with status={}", err.status);
-            }
-        }
+    let joined = match &config {
+        Some(config) => join_network(&mut control, config).await,
+        None => false,
+    };
+    if !joined {
+        let passphrase = config.as_ref().map_or(DEFAULT_AP_PASSPHRASE, |config| {
+            config.ap_passphrase.as_str()
+        });
+        start_access_point(stack, &mut control, passphrase).await;
     }
 
     let mut wait_time = 1;
@@ -117,52 +241,294 @@ pub async fn transmitter(
         warn!("Failed to configure network, trying again...");
     }
 
-    let mut rx_buffer = [0; 4096];
-    let mut tx_buffer = [0; 4096];
-    let _buf = [0; 4096];
+    // `control` is only needed to bring the network up, so it goes back to blinking the LED.
+    let spawner = Spawner::for_current_executor().await;
+    unwrap!(spawner.spawn(blinker(control)));
 
+    let port = config.map_or(DEFAULT_PORT, |config| config.port);
+    let serve = serve(stack, port, &hello, telemetry, commands);
+    if joined {
+        serve.await;
+    } else {
+        join(serve, crate::dhcp::serve(stack)).await;
+    }
+}
+
+async fn join_network(control: &mut Control<'_>, config: &DeviceConfig) -> bool {
+    for attempt in 1..=JOIN_ATTEMPTS {
+        match control
+            .join(&config.ssid, JoinOptions::new(config.passphrase.as_bytes()))
+            .await
+        {
+            Ok(_) => {
+                info!("Joined network!");
+                return true;
+            }
+            Err(err) => {
+                info!("join attempt {} failed with status={}", attempt, err.status);
+            }
+        }
+    }
+    false
+}
+
+async fn start_access_point(
+    stack: embassy_net::Stack<'_>,
+    control: &mut Control<'_>,
+    passphrase: &str,
+) {
+    warn!("Starting access point {}", AP_SSID);
+    let [a, b, c, d] = AP_ADDRESS;
+    stack.set_config_v4(ConfigV4::Static(StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), AP_PREFIX_LEN),
+        gateway: None,
+        dns_servers: Default::default(),
+    }));
+    control.start_ap_wpa2(AP_SSID, passphrase, AP_CHANNEL).await;
+}
+
+/// Serves up to `MAX_CLIENTS` clients at once on `port`, see `client`. Datagrams to all
+/// of them go out through one socket.
+async fn serve(
+    stack: embassy_net::Stack<'_>,
+    port: u16,
+    hello: &HelloMessage,
+    telemetry: &Telemetry,
+    commands: &CommandQueue,
+) -> ! {
+    let mut udp_rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut udp_rx_buffer = [0; 64];
+    let mut udp_tx_meta = [PacketMetadata::EMPTY; 8];
+    let mut udp_tx_buffer = [0; 4096];
+    let mut udp = UdpSocket::new(
+        stack,
+        &mut udp_rx_meta,
+        &mut udp_rx_buffer,
+        &mut udp_tx_meta,
+        &mut udp_tx_buffer,
+    );
+    unwrap!(udp.bind(port));
+
+    let clients: [_; MAX_CLIENTS] =
+        core::array::from_fn(|_| client(stack, port, &udp, hello, telemetry, commands));
+    let [first, ..] = join_array(clients).await;
+    first
+}
+
+/// Serves one client at a time on `port`, greeting it with `hello` and the current
+/// `GAINS`, streaming `telemetry` to it and queueing the commands it sends on `commands`.
+/// A client that stops acknowledging, keepalives included, is dropped after
+/// `CLIENT_TIMEOUT`.
+async fn client(
+    stack: embassy_net::Stack<'_>,
+    port: u16,
+    udp: &UdpSocket<'_>,
+    hello: &HelloMessage,
+    telemetry: &Telemetry,
+    commands: &CommandQueue,
+) -> ! {
+    // Commands are a few bytes each.
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 4096];
     let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
-    // socket.set_timeout(Some(Duration::from_secs(15)));
 
     loop {
-        // control.gpio_set(0, false).await;
-        info!("Listening on TCP at port 1234...");
-        if let Err(e) = socket.accept(1234).await {
+        socket.set_timeout(Some(CLIENT_TIMEOUT));
+        socket.set_keep_alive(Some(KEEP_ALIVE_INTERVAL));
+        info!("Listening on TCP at port {}...", port);
+        if let Err(e) = socket.accept(port).await {
             warn!("accept error: {:?}", e);
             continue;
         }
 
-        info!("Received connection from {:?}", socket.remote_endpoint());
-        let _ = GOT_CONNECTION.enqueue(());
-        // control.gpio_set(0, true).await;
+        let Some(client) = socket.remote_endpoint() else {
+            close(&mut socket).await;
+            continue;
+        };
+        let Some(subscription) = telemetry.subscribe() else {
+            warn!("Turning away {:?}, too many clients", client);
+            close(&mut socket).await;
+            continue;
+        };
+        info!(
+            "Received connection from {:?}, {} clients",
+            client,
+            telemetry.clients()
+        );
+        if let Err(e) = greet(&mut socket, hello).await {
+            warn!("write error: {:?}", e);
+            close(&mut socket).await;
+            continue;
+        }
+        let _ = GOT_CONNECTION.enqueue(subscription.id());
 
-        loop {
-            if let Some(msg) = channel.dequeue() {
-                let mut formatted = [0_u8; 32];
+        let transport = Cell::new(Transport::Tcp);
+        let (mut reader, writer) = socket.split();
+        let mut link = Link {
+            tcp: writer,
+            udp,
+            client: client.addr,
+            transport: &transport,
+        };
+        match select(
+            stream(&mut link, &subscription),
+            receive(&mut reader, &transport, telemetry, &subscription, commands),
+        )
+        .await
+        {
+            Either::First(Err(e)) => warn!("write error to {:?}: {:?}", client, e),
+            Either::Second(Err(e)) => warn!("read error from {:?}: {:?}", client, e),
+            Either::Second(Ok(())) => info!("Connection closed by {:?}", client),
+        }
+        drop(subscription);
+        close(&mut socket).await;
+    }
+}
 
-                let Ok(n) = bincode::serde::encode_into_slice(
-                    msg,
-                    &mut formatted[2..],
-                    bincode::config::standard(),
-                ) else {
-                    warn!("Failed to encode msg");
+async fn close(socket: &mut TcpSocket<'_>) {
+    socket.abort();
+    let _ = socket.flush().await;
+}
+
+async fn greet(
+    socket: &mut TcpSocket<'_>,
+    hello: &HelloMessage,
+) -> Result<(), embassy_net::tcp::Error> {
+    let hello = HelloMessage {
+        gains: GAINS.load(),
+        ..hello.clone()
+    };
+    let mut payload = [0_u8; framing::MAX_MESSAGE_LEN];
+    let mut frame = [0_u8; framing::MAX_FRAME_LEN];
+    match framing::encode_message(&hello, &mut payload, &mut frame) {
+        Some(n) => socket.write_all(&frame[..n]).await,
+        None => {
+            warn!("Failed to encode hello");
+            Ok(())
+        }
+    }
+}
+
+/// Where the telemetry for a client goes.
+struct Link<'a> {
+    tcp: TcpWriter<'a>,
+    udp: &'a UdpSocket<'a>,
+    client: IpAddress,
+    transport: &'a Cell<Transport>,
+}
+
+impl Link<'_> {
+    /// Sends `batch` over the current transport. Only TCP errors are returned, as they
+    /// end the connection, while a lost datagram is just lost.
+    async fn send(&mut self, batch: &[u8]) -> Result<(), embassy_net::tcp::Error> {
+        match self.transport.get() {
+            Transport::Tcp => self.tcp.write_all(batch).await,
+            Transport::Udp { port } => {
+                if let Err(e) = self
+                    .udp
+                    .send_to(batch, IpEndpoint::new(self.client, port))
+                    .await
+                {
+                    warn!("udp send error: {:?}", e);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Sends everything queued for `subscription` in batches of frames until a write fails.
+/// Pongs are stamped with the time they leave the queue.
+async fn stream(
+    link: &mut Link<'_>,
+    subscription: &Subscription<'_>,
+) -> Result<core::convert::Infallible, embassy_net::tcp::Error> {
+    let mut batch = [0_u8; BATCH_SIZE];
+    let mut payload = [0_u8; framing::MAX_MESSAGE_LEN];
+    let mut frame = [0_u8; framing::MAX_FRAME_LEN];
+    loop {
+        let mut len = 0;
+        while let Some(mut msg) = subscription.dequeue() {
+            if let LogMessage::Pong(pong) = &mut msg.msg {
+                // Leaves out the time spent in the queue, which is the bulk of it.
+                pong.sent_us = Instant::now().as_micros();
+            }
+            let Some(n) = framing::encode_message(&msg, &mut payload, &mut frame) else {
+                warn!("Failed to encode msg");
+                continue;
+            };
+            if len + n > batch.len() {
+                link.send(&batch[..len]).await?;
+                len = 0;
+            }
+            batch[len..len + n].copy_from_slice(&frame[..n]);
+            len += n;
+        }
+        if len > 0 {
+            link.send(&batch[..len]).await?;
+        }
+        Timer::after_millis(BATCH_INTERVAL_MS).await;
+    }
+}
+
+/// Decodes commands from the client until the connection is closed. Transport changes
+/// are handled here, everything else is queued on `commands` for the control loop.
+async fn receive(
+    socket: &mut TcpReader<'_>,
+    transport: &Cell<Transport>,
+    telemetry: &Telemetry,
+    subscription: &Subscription<'_>,
+    commands: &CommandQueue,
+) -> Result<(), embassy_net::tcp::Error> {
+    let mut decoder = framing::Decoder::<{ framing::MAX_FRAME_LEN }>::new();
+    let mut buf = [0_u8; 64];
+    loop {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        for &byte in &buf[..n] {
+            let request = match decoder.push(byte) {
+                Some(Ok(payload)) => match framing::decode_message::<CommandRequest>(payload) {
+                    Ok(request) => request,
+                    Err(_) => {
+                        warn!("Ignoring unknown command");
+                        continue;
+                    }
+                },
+                Some(Err(e)) => {
+                    warn!("Ignoring corrupt command: {}", Debug2Format(&e));
                     continue;
-                };
-                let size_bytes = (n as u16).to_be_bytes();
-                formatted[0] = size_bytes[0];
-                formatted[1] = size_bytes[1];
-
-                // info!("sending {:?}", formatted[..n+2]);
-                match socket.write_all(&formatted[..n + 2]).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        warn!("write error: {:?}", e);
-                        break;
+                }
+                None => continue,
+            };
+
+            match request.command {
+                CommandMessage::SetTransport(new_transport) => {
+                    info!("Streaming over {}", Debug2Format(&new_transport));
+                    transport.set(new_transport);
+                    telemetry.publish_to(
+                        subscription.id(),
+                        LogMessage::Ack(AckMessage {
+                            id: request.id,
+                            result: Ok(()),
+                        }),
+                    );
+                }
+                CommandMessage::Ping { host_time_us } => telemetry.publish_to(
+                    subscription.id(),
+                    LogMessage::Pong(PongMessage {
+                        host_time_us,
+                        received_us: Instant::now().as_micros(),
+                        // Set by `stream`.
+                        sent_us: 0,
+                    }),
+                ),
+                _ => {
+                    if commands.enqueue((subscription.id(), request)).is_err() {
+                        crate::log!(telemetry, Warn, "Command queue full, dropping command");
                     }
                 }
-                Timer::after_millis(5).await;
-            } else {
-                Timer::after_millis(5).await;
             }
         }
     }
@@ -0,0 +1,149 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use common::{Envelope, LogMessage};
+use heapless::mpmc;
+
+/// Clients that can receive telemetry at once.
+pub const MAX_CLIENTS: usize = 3;
+
+/// Identifies the client a command came from, so the reply goes back to it alone.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub struct ClientId(usize);
+
+/// Messages waiting for one client, numbered in the order they were queued.
+struct Subscriber {
+    /// Holds a bit more than half a second of telemetry, enough to ride out a short WiFi
+    /// stall.
+    queue: mpmc::Q64<Envelope>,
+    /// Whether a client is connected and taking messages from `queue`.
+    active: AtomicBool,
+    /// Sequence number of the next queued message.
+    sequence: AtomicU32,
+    /// Number of messages that did not fit in `queue` since the client subscribed.
+    dropped: AtomicU32,
+}
+
+impl Subscriber {
+    const fn new() -> Self {
+        Subscriber {
+            queue: mpmc::Q64::new(),
+            active: AtomicBool::new(false),
+            sequence: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns whether `msg` was dropped. A dropped message still uses up its sequence
+    /// number, which is how the receiver notices.
+    fn enqueue(&self, msg: LogMessage) -> bool {
+        let envelope = Envelope {
+            seq: self.sequence.fetch_add(1, Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            msg,
+        };
+        let dropped = self.queue.enqueue(envelope).is_err();
+        if dropped {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        dropped
+    }
+}
+
+/// Messages waiting for the transmitter, copied to every connected client.
+pub struct Telemetry {
+    subscribers: [Subscriber; MAX_CLIENTS],
+    /// Number of messages dropped for any client since boot.
+    dropped: AtomicU32,
+}
+
+impl Telemetry {
+    pub const fn new() -> Self {
+        Telemetry {
+            subscribers: [const { Subscriber::new() }; MAX_CLIENTS],
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Starts queueing messages for a new client, or returns `None` if `MAX_CLIENTS` are
+    /// already connected. Messages published before that are not seen by the client.
+    pub fn subscribe(&self) -> Option<Subscription<'_>> {
+        let (i, subscriber) = self.subscribers.iter().enumerate().find(|(_, s)| {
+            s.active
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        })?;
+        while subscriber.queue.dequeue().is_some() {}
+        subscriber.sequence.store(0, Ordering::Relaxed);
+        subscriber.dropped.store(0, Ordering::Relaxed);
+        Some(Subscription {
+            telemetry: self,
+            id: ClientId(i),
+        })
+    }
+
+    /// Queues `msg` for every connected client without waiting, so the caller is never
+    /// held up by the network.
+    pub fn publish(&self, msg: LogMessage) {
+        for (i, subscriber) in self.subscribers.iter().enumerate() {
+            if subscriber.active.load(Ordering::Relaxed) {
+                self.enqueue(ClientId(i), msg.clone());
+            }
+        }
+    }
+
+    /// Queues `msg` for `client` only, if it is still connected.
+    pub fn publish_to(&self, client: ClientId, msg: LogMessage) {
+        if self.subscribers[client.0].active.load(Ordering::Relaxed) {
+            self.enqueue(client, msg);
+        }
+    }
+
+    fn enqueue(&self, client: ClientId, msg: LogMessage) {
+        if self.subscribers[client.0].enqueue(msg) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of clients currently receiving telemetry.
+    pub fn clients(&self) -> usize {
+        self.subscribers
+            .iter()
+            .filter(|s| s.active.load(Ordering::Relaxed))
+            .count()
+    }
+
+    /// Number of messages dropped since boot because a client's queue was full.
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A client's share of the telemetry, given up when dropped.
+pub struct Subscription<'a> {
+    telemetry: &'a Telemetry,
+    id: ClientId,
+}
+
+impl Subscription<'_> {
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+
+    pub fn dequeue(&self) -> Option<Envelope> {
+        self.telemetry.subscribers[self.id.0].queue.dequeue()
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        self.telemetry.subscribers[self.id.0]
+            .active
+            .store(false, Ordering::Release);
+    }
+}
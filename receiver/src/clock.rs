@@ -0,0 +1,145 @@
+//! Relating the device clock, the one every `time_ms` is read from, to the host's from
+//! `CommandMessage::Ping` and `PongMessage` exchanges.
+
+use common::PongMessage;
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Exchanges the estimate is picked from. Enough to find one that was not held up, few
+/// enough that the clocks do not drift apart meanwhile.
+const WINDOW: usize = 16;
+
+/// Host time in microseconds since the Unix epoch.
+pub fn unix_time_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// What one ping/pong exchange tells about the clocks.
+#[derive(Clone, Copy, Debug)]
+pub struct Exchange {
+    /// Host time minus device time, assuming the link is as fast both ways.
+    pub offset_us: i64,
+    /// Time spent on the link, not counting the time on the device.
+    pub rtt_us: i64,
+}
+
+/// The most recent exchanges with the device.
+pub struct ClockSync {
+    exchanges: VecDeque<Exchange>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        ClockSync {
+            exchanges: VecDeque::with_capacity(WINDOW),
+        }
+    }
+
+    /// Accounts for `pong`, received at `received_us` host time.
+    pub fn update(&mut self, pong: &PongMessage, received_us: u64) {
+        let sent = pong.host_time_us as i64;
+        let device_received = pong.received_us as i64;
+        let device_sent = pong.sent_us as i64;
+        let received = received_us as i64;
+        let exchange = Exchange {
+            offset_us: ((sent - device_received) + (received - device_sent)) / 2,
+            rtt_us: (received - sent) - (device_sent - device_received),
+        };
+        if self.exchanges.len() == WINDOW {
+            self.exchanges.pop_front();
+        }
+        self.exchanges.push_back(exchange);
+    }
+
+    pub fn latest(&self) -> Option<Exchange> {
+        self.exchanges.back().copied()
+    }
+
+    /// The recent exchange with the shortest round trip, which waited least in queues
+    /// and so splits the delay most evenly between both ways.
+    pub fn estimate(&self) -> Option<Exchange> {
+        self.exchanges.iter().copied().min_by_key(|e| e.rtt_us)
+    }
+
+    /// Host time in microseconds since the Unix epoch at device time `device_us`, once
+    /// there has been an exchange.
+    pub fn host_time_us(&self, device_us: u64) -> Option<i64> {
+        Some(device_us as i64 + self.estimate()?.offset_us)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Host clock 1 s ahead of the device's.
+    const OFFSET_US: u64 = 1_000_000;
+
+    /// A pong for a ping sent at host time `sent_us`, taking `up_us` to reach the device,
+    /// `on_device_us` there and `down_us` back. Returns it with the host time it arrives.
+    fn pong(sent_us: u64, up_us: u64, on_device_us: u64, down_us: u64) -> (PongMessage, u64) {
+        let received_us = sent_us + up_us - OFFSET_US;
+        let pong = PongMessage {
+            host_time_us: sent_us,
+            received_us,
+            sent_us: received_us + on_device_us,
+        };
+        let arrived_us = pong.sent_us + OFFSET_US + down_us;
+        (pong, arrived_us)
+    }
+
+    fn update(clock: &mut ClockSync, (pong, received_us): (PongMessage, u64)) {
+        clock.update(&pong, received_us);
+    }
+
+    #[test]
+    fn symmetric_delay_gives_the_exact_offset() {
+        let mut clock = ClockSync::new();
+        assert!(clock.estimate().is_none());
+        update(&mut clock, pong(5_000_000, 400, 30, 400));
+
+        let exchange = clock.estimate().unwrap();
+        assert_eq!(exchange.offset_us, OFFSET_US as i64);
+        assert_eq!(exchange.rtt_us, 800);
+        assert_eq!(
+            clock.host_time_us(2_000_000),
+            Some(2_000_000 + OFFSET_US as i64)
+        );
+    }
+
+    #[test]
+    fn queueing_one_way_is_outweighed_by_a_faster_exchange() {
+        let mut clock = ClockSync::new();
+        // Held up for 10 ms on the way back, half of which ends up in the offset.
+        update(&mut clock, pong(5_000_000, 500, 30, 10_500));
+        let exchange = clock.latest().unwrap();
+        assert_eq!(exchange.offset_us, OFFSET_US as i64 + 5_000);
+        assert_eq!(exchange.rtt_us, 11_000);
+
+        update(&mut clock, pong(6_000_000, 600, 30, 400));
+        update(&mut clock, pong(7_000_000, 20_000, 30, 500));
+        let estimate = clock.estimate().unwrap();
+        assert_eq!(estimate.rtt_us, 1_000);
+        assert_eq!(estimate.offset_us, OFFSET_US as i64 - 100);
+        assert_eq!(clock.latest().unwrap().rtt_us, 20_500);
+    }
+
+    #[test]
+    fn old_exchanges_leave_the_window() {
+        let mut clock = ClockSync::new();
+        update(&mut clock, pong(1_000_000, 100, 30, 100));
+        for i in 0..WINDOW as u64 {
+            update(
+                &mut clock,
+                pong(2_000_000 + i * 1_000_000, 2_000, 30, 1_000),
+            );
+        }
+        // The fastest exchange is gone, the best of the rest was held up on the way out.
+        let estimate = clock.estimate().unwrap();
+        assert_eq!(estimate.rtt_us, 3_000);
+        assert_eq!(estimate.offset_us, OFFSET_US as i64 - 500);
+    }
+}
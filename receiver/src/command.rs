@@ -0,0 +1,61 @@
+//! Sending commands to the firmware over the telemetry connection.
+
+use std::io::Write;
+use std::net::TcpStream;
+
+use common::{CommandMessage, CommandRequest, framing};
+
+pub const HELP: &str = "\
+commands:
+  stop                 turn the motor off
+  swing                clear faults and start swinging up
+  bench                turn the motor off and accept `output`
+  output <u>           hold the motor at u in [-1, 1], bench mode only
+  gains <k0> <k1> <k2> set the balancing gains
+  status               request a status message";
+
+/// Writes commands to the firmware. The acknowledgements arrive as `LogMessage::Ack`
+/// on the telemetry stream.
+pub struct CommandSender {
+    stream: TcpStream,
+    next_id: u32,
+}
+
+impl CommandSender {
+    pub fn new(stream: TcpStream) -> Self {
+        CommandSender { stream, next_id: 0 }
+    }
+
+    /// Sends `command` and returns the id its acknowledgement will carry.
+    pub fn send(&mut self, command: CommandMessage) -> anyhow::Result<u32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut payload = [0_u8; framing::MAX_MESSAGE_LEN];
+        let mut frame = [0_u8; framing::MAX_FRAME_LEN];
+        let n = framing::encode_message(&CommandRequest { id, command }, &mut payload, &mut frame)
+            .ok_or_else(|| anyhow::anyhow!("command does not fit in a frame"))?;
+        self.stream.write_all(&frame[..n])?;
+        Ok(id)
+    }
+}
+
+/// Parses a command line as described in `HELP`.
+pub fn parse(line: &str) -> Result<CommandMessage, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().ok_or("empty command")?;
+    let args = words
+        .map(|w| w.parse::<f32>().map_err(|e| format!("{w:?}: {e}")))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let command = match (name, args.as_slice()) {
+        ("stop", []) => CommandMessage::StopMotor,
+        ("swing", []) => CommandMessage::StartSwingUp,
+        ("bench", []) => CommandMessage::EnterBenchMode,
+        ("output", &[u]) => CommandMessage::SetMotorOutput(u),
+        ("gains", &[k0, k1, k2]) => CommandMessage::SetGains([k0, k1, k2]),
+        ("status", []) => CommandMessage::RequestStatus,
+        _ => return Err(format!("unknown command {line:?}")),
+    };
+    Ok(command)
+}
@@ -0,0 +1,105 @@
+//! Finds devices on the local network from their announcements, see
+//! `common::discovery`.
+
+use common::PROTOCOL_VERSION;
+use common::discovery::{self, Announcement};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub name: String,
+    /// Address of the device's TCP server.
+    pub address: SocketAddr,
+    pub protocol_version: u16,
+}
+
+impl Device {
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+struct Listener {
+    socket: UdpSocket,
+}
+
+impl Listener {
+    fn bind() -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", discovery::PORT)).map_err(|e| {
+            anyhow::anyhow!(
+                "failed to listen for devices on port {}: {e}",
+                discovery::PORT
+            )
+        })?;
+        Ok(Listener { socket })
+    }
+
+    /// Waits until `deadline` for the next announcement.
+    fn next(&self, deadline: Instant) -> anyhow::Result<Option<Device>> {
+        let mut buf = [0_u8; 1500];
+        loop {
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                return Ok(None);
+            };
+            // A zero timeout would block forever.
+            self.socket
+                .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+            let (n, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if crate::is_timeout(&e) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            match Announcement::parse(&buf[..n]) {
+                Some(Ok(announcement)) => {
+                    return Ok(Some(Device {
+                        name: announcement.name.to_string(),
+                        address: SocketAddr::new(from.ip(), announcement.port),
+                        protocol_version: announcement.protocol_version,
+                    }));
+                }
+                Some(Err(e)) => println!("Ignoring invalid announcement from {from}: {e:?}"),
+                None => {}
+            }
+        }
+    }
+}
+
+/// Every device heard from within `duration`.
+pub fn list(duration: Duration) -> anyhow::Result<Vec<Device>> {
+    let listener = Listener::bind()?;
+    let deadline = Instant::now() + duration;
+    let mut devices = Vec::new();
+    while let Some(device) = listener.next(deadline)? {
+        if !devices.contains(&device) {
+            devices.push(device);
+        }
+    }
+    Ok(devices)
+}
+
+/// The first compatible device named `name`, or any if `name` is `None`, to announce
+/// itself within `timeout`.
+pub fn find(name: Option<&str>, timeout: Duration) -> anyhow::Result<Device> {
+    let listener = Listener::bind()?;
+    let deadline = Instant::now() + timeout;
+    while let Some(device) = listener.next(deadline)? {
+        if name.is_some_and(|name| name != device.name) {
+            continue;
+        }
+        if device.is_compatible() {
+            return Ok(device);
+        }
+        println!(
+            "Skipping {} at {}, it speaks protocol version {}",
+            device.name, device.address, device.protocol_version
+        );
+    }
+    match name {
+        Some(name) => anyhow::bail!("no device named {name:?} found"),
+        None => anyhow::bail!(
+            "no device found, pass --ap when on the device's own network {:?}",
+            common::config::AP_SSID
+        ),
+    }
+}
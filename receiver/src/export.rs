@@ -0,0 +1,426 @@
+//! Flat tables of the controller and bench messages for analysis outside rerun.
+//!
+//! Each message type becomes one table in the output directory, `controller.csv` and
+//! `bench.csv` or `controller/part-NNNN.parquet` and `bench/part-NNNN.parquet`.
+//! `time_s` is device time in seconds, which starts over when the device restarts, and
+//! `host_time_s` the same instant in seconds since the Unix epoch, which keeps going
+//! across connections. The rest of the columns are named after the rerun entities they
+//! are also logged to.
+
+use crate::clock::ClockSync;
+use arrow_array::{ArrayRef, Float32Array, Float64Array, RecordBatch, StringArray};
+use arrow_cast::display::{ArrayFormatter, FormatOptions};
+use common::{BenchMessage, ControllerMessage, LogMessage};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long rows may stay buffered before they are written.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rows per Parquet file. A file can only be read once it is closed, so this bounds
+/// what is lost if the receiver is killed, a minute at the controller's rate.
+const PART_ROWS: usize = 6000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+fn seconds<T>(rows: &[T], time_ms: impl Fn(&T) -> u64) -> ArrayRef {
+    Arc::new(Float64Array::from_iter_values(
+        rows.iter().map(|row| time_ms(row) as f64 / 1000.0),
+    ))
+}
+
+fn host_seconds(host_times_us: &[i64]) -> ArrayRef {
+    Arc::new(Float64Array::from_iter_values(
+        host_times_us.iter().map(|&us| us as f64 * 1e-6),
+    ))
+}
+
+fn floats<T>(rows: &[T], value: impl Fn(&T) -> f32) -> ArrayRef {
+    Arc::new(Float32Array::from_iter_values(rows.iter().map(value)))
+}
+
+fn controller_batch(rows: &[ControllerMessage], host_times_us: &[i64]) -> RecordBatch {
+    RecordBatch::try_from_iter([
+        ("time_s", seconds(rows, |m| m.time_ms)),
+        ("host_time_s", host_seconds(host_times_us)),
+        (
+            "state",
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|m| format!("{:?}", m.state)),
+            )) as ArrayRef,
+        ),
+        ("control", floats(rows, |m| m.control)),
+        ("sensor_pend_angle", floats(rows, |m| m.sensor_pend_angle)),
+        ("sensor_velocity", floats(rows, |m| m.sensor_wheel_velocity)),
+        ("pend_angle", floats(rows, |m| m.pend_angle)),
+        ("pend_velocity", floats(rows, |m| m.pend_velocity)),
+        ("wheel_velocity", floats(rows, |m| m.wheel_velocity)),
+    ])
+    .unwrap()
+}
+
+fn bench_batch(rows: &[BenchMessage], host_times_us: &[i64]) -> RecordBatch {
+    RecordBatch::try_from_iter([
+        ("time_s", seconds(rows, |m| m.time_ms)),
+        ("host_time_s", host_seconds(host_times_us)),
+        ("control", floats(rows, |m| m.control)),
+        ("wheel_velocity", floats(rows, |m| m.signed_rot_speed)),
+        ("abs_wheel_velocity", floats(rows, |m| m.abs_rot_speed)),
+    ])
+    .unwrap()
+}
+
+enum Writer {
+    Csv(BufWriter<File>),
+    Parquet {
+        writer: Box<ArrowWriter<File>>,
+        rows: usize,
+    },
+}
+
+/// One message type, buffered and written in batches.
+struct Table<T> {
+    path: PathBuf,
+    format: Format,
+    to_batch: fn(&[T], &[i64]) -> RecordBatch,
+    rows: Vec<T>,
+    /// Host time of each row in microseconds since the Unix epoch.
+    host_times_us: Vec<i64>,
+    writer: Option<Writer>,
+    part: u32,
+}
+
+impl<T> Table<T> {
+    fn new(
+        dir: &Path,
+        name: &str,
+        format: Format,
+        to_batch: fn(&[T], &[i64]) -> RecordBatch,
+    ) -> Self {
+        let path = match format {
+            Format::Csv => dir.join(format!("{name}.csv")),
+            Format::Parquet => dir.join(name),
+        };
+        Table {
+            path,
+            format,
+            to_batch,
+            rows: Vec::new(),
+            host_times_us: Vec::new(),
+            writer: None,
+            part: 0,
+        }
+    }
+
+    fn open(&mut self, batch: &RecordBatch) -> anyhow::Result<Writer> {
+        match self.format {
+            Format::Csv => {
+                // The first time the file is replaced, after `close` it is continued.
+                let first = self.part == 0;
+                self.part += 1;
+                let file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(first)
+                    .append(!first)
+                    .open(&self.path)?;
+                let mut out = BufWriter::new(file);
+                if first {
+                    let names: Vec<_> = batch
+                        .schema()
+                        .fields()
+                        .iter()
+                        .map(|field| field.name().clone())
+                        .collect();
+                    writeln!(out, "{}", names.join(","))?;
+                }
+                Ok(Writer::Csv(out))
+            }
+            Format::Parquet => {
+                std::fs::create_dir_all(&self.path)?;
+                let file = File::create(self.path.join(format!("part-{:04}.parquet", self.part)))?;
+                self.part += 1;
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .set_max_row_group_size(PART_ROWS)
+                    .build();
+                Ok(Writer::Parquet {
+                    writer: Box::new(ArrowWriter::try_new(
+                        file,
+                        batch.schema(),
+                        Some(properties),
+                    )?),
+                    rows: 0,
+                })
+            }
+        }
+    }
+
+    /// Writes the buffered rows.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let batch = (self.to_batch)(&self.rows, &self.host_times_us);
+        self.rows.clear();
+        self.host_times_us.clear();
+        let writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => self.open(&batch)?,
+        };
+        self.writer = match writer {
+            Writer::Csv(mut out) => {
+                write_csv(&mut out, &batch)?;
+                out.flush()?;
+                Some(Writer::Csv(out))
+            }
+            Writer::Parquet {
+                mut writer,
+                mut rows,
+            } => {
+                writer.write(&batch)?;
+                rows += batch.num_rows();
+                if rows >= PART_ROWS {
+                    writer.close()?;
+                    None
+                } else {
+                    Some(Writer::Parquet { writer, rows })
+                }
+            }
+        };
+        Ok(())
+    }
+
+    /// Writes the buffered rows and closes the file, so everything so far can be read.
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.flush()?;
+        match self.writer.take() {
+            Some(Writer::Csv(mut out)) => out.flush()?,
+            Some(Writer::Parquet { writer, .. }) => {
+                writer.close()?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+fn write_csv(out: &mut impl Write, batch: &RecordBatch) -> anyhow::Result<()> {
+    let options = FormatOptions::default();
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|column| ArrayFormatter::try_new(column.as_ref(), &options))
+        .collect::<Result<Vec<_>, _>>()?;
+    for row in 0..batch.num_rows() {
+        for (i, column) in columns.iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write!(out, "{}", column.value(row))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Writes the controller and bench messages it is given to tables in a directory.
+pub struct Exporter {
+    controller: Table<ControllerMessage>,
+    bench: Table<BenchMessage>,
+    last_flush: Instant,
+}
+
+impl Exporter {
+    pub fn create(dir: &Path, format: Format) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Exporter {
+            controller: Table::new(dir, "controller", format, controller_batch),
+            bench: Table::new(dir, "bench", format, bench_batch),
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Adds `msg`, which arrived at `arrival_us` host time, to its table. Other messages
+    /// are ignored. The host time of a row is taken from `clock`, or is the time of
+    /// arrival until the clocks have been compared.
+    pub fn push(
+        &mut self,
+        msg: &LogMessage,
+        clock: &ClockSync,
+        arrival_us: u64,
+    ) -> anyhow::Result<()> {
+        let host_time_us = |time_ms: u64| {
+            clock
+                .host_time_us(time_ms * 1000)
+                .unwrap_or(arrival_us as i64)
+        };
+        match msg {
+            LogMessage::Controller(msg) => {
+                self.controller.rows.push(msg.clone());
+                self.controller
+                    .host_times_us
+                    .push(host_time_us(msg.time_ms));
+            }
+            LogMessage::Bench(msg) => {
+                self.bench.rows.push(msg.clone());
+                self.bench.host_times_us.push(host_time_us(msg.time_ms));
+            }
+            _ => {}
+        }
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.controller.flush()?;
+            self.bench.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Writes everything pushed so far and closes the files. Later messages go to new
+    /// Parquet parts, or are appended to the CSV files.
+    pub fn close(&mut self) -> anyhow::Result<()> {
+        self.controller.close()?;
+        self.bench.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::PongMessage;
+    use common::control::BalancingState;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("export-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn controller(time_ms: u64) -> LogMessage {
+        LogMessage::Controller(ControllerMessage {
+            time_ms,
+            state: BalancingState::Balancing,
+            control: 0.5,
+            sensor_pend_angle: 1.0,
+            sensor_wheel_velocity: 2.0,
+            pend_angle: 3.0,
+            pend_velocity: 4.0,
+            wheel_velocity: 5.0,
+        })
+    }
+
+    fn bench(time_ms: u64) -> LogMessage {
+        LogMessage::Bench(BenchMessage {
+            time_ms,
+            control: -0.25,
+            signed_rot_speed: -8.0,
+            abs_rot_speed: 8.0,
+        })
+    }
+
+    #[test]
+    fn csv_rows_follow_one_header_across_connections() {
+        let dir = temp_dir("csv");
+        let mut exporter = Exporter::create(&dir, Format::Csv).unwrap();
+        let mut clock = ClockSync::new();
+        // Before the clocks are compared rows get their time of arrival.
+        exporter.push(&controller(1000), &clock, 5_000_000).unwrap();
+        exporter.push(&bench(1500), &clock, 5_500_000).unwrap();
+        exporter
+            .push(&LogMessage::Alive, &clock, 5_600_000)
+            .unwrap();
+        exporter.close().unwrap();
+
+        // The device restarted, with its clock 10 s behind the host's.
+        let pong = PongMessage {
+            host_time_us: 9_999_000,
+            received_us: 0,
+            sent_us: 0,
+        };
+        clock.update(&pong, 10_001_000);
+        exporter.push(&controller(500), &clock, 10_600_000).unwrap();
+        exporter.close().unwrap();
+
+        let controller = std::fs::read_to_string(dir.join("controller.csv")).unwrap();
+        let bench = std::fs::read_to_string(dir.join("bench.csv")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            controller.lines().collect::<Vec<_>>(),
+            [
+                "time_s,host_time_s,state,control,sensor_pend_angle,sensor_velocity,\
+                 pend_angle,pend_velocity,wheel_velocity",
+                "1.0,5.0,Balancing,0.5,1.0,2.0,3.0,4.0,5.0",
+                "0.5,10.5,Balancing,0.5,1.0,2.0,3.0,4.0,5.0",
+            ]
+        );
+        assert_eq!(
+            bench.lines().collect::<Vec<_>>(),
+            [
+                "time_s,host_time_s,control,wheel_velocity,abs_wheel_velocity",
+                "1.5,5.5,-0.25,-8.0,8.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn parquet_parts_carry_the_schema() {
+        let dir = temp_dir("parquet");
+        let mut exporter = Exporter::create(&dir, Format::Parquet).unwrap();
+        let clock = ClockSync::new();
+        for i in 0..3 {
+            exporter.push(&controller(i * 10), &clock, i).unwrap();
+        }
+        exporter.push(&bench(0), &clock, 0).unwrap();
+        exporter.close().unwrap();
+
+        let read = |table: &str| {
+            let file = File::open(dir.join(table).join("part-0000.parquet")).unwrap();
+            let reader = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+            let schema = reader.schema().clone();
+            let rows: usize = reader.build().unwrap().map(|b| b.unwrap().num_rows()).sum();
+            let names: Vec<String> = schema.fields().iter().map(|f| f.name().clone()).collect();
+            (names, rows)
+        };
+        let (controller_columns, controller_rows) = read("controller");
+        let (bench_columns, bench_rows) = read("bench");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            controller_columns,
+            [
+                "time_s",
+                "host_time_s",
+                "state",
+                "control",
+                "sensor_pend_angle",
+                "sensor_velocity",
+                "pend_angle",
+                "pend_velocity",
+                "wheel_velocity"
+            ]
+        );
+        assert_eq!(controller_rows, 3);
+        assert_eq!(
+            bench_columns,
+            [
+                "time_s",
+                "host_time_s",
+                "control",
+                "wheel_velocity",
+                "abs_wheel_velocity"
+            ]
+        );
+        assert_eq!(bench_rows, 1);
+    }
+}
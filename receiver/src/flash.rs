@@ -0,0 +1,72 @@
+//! Images of the config sector to flash onto the device, see `common::config`.
+
+use common::config::{DeviceConfig, FLASH_ADDRESS, MAX_RECORD_LEN, SECTOR_LEN};
+use std::path::Path;
+
+const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+const UF2_FLAG_FAMILY_ID: u32 = 0x2000;
+/// Makes the RP2350 boot ROM write the data where it says rather than treating it as a
+/// program image.
+const RP2350_ABSOLUTE_FAMILY_ID: u32 = 0xE48B_FF57;
+const UF2_PAYLOAD_LEN: usize = 256;
+
+/// The whole config sector, erased after the record.
+fn sector(config: &DeviceConfig) -> anyhow::Result<Vec<u8>> {
+    let mut sector = vec![0xFF; SECTOR_LEN];
+    let n = config
+        .encode(&mut sector[..MAX_RECORD_LEN])
+        .ok_or_else(|| anyhow::anyhow!("config does not fit in {MAX_RECORD_LEN} bytes"))?;
+    debug_assert_eq!(DeviceConfig::decode(&sector[..n]).as_ref(), Ok(config));
+    Ok(sector)
+}
+
+/// Wraps `data` in UF2 blocks that write it to `address`.
+fn uf2(data: &[u8], address: u32) -> Vec<u8> {
+    let blocks = data.chunks(UF2_PAYLOAD_LEN);
+    let count = blocks.len() as u32;
+    let mut out = Vec::with_capacity(count as usize * 512);
+    for (i, payload) in blocks.enumerate() {
+        let mut block = [0_u8; 512];
+        let header = [
+            UF2_MAGIC_START0,
+            UF2_MAGIC_START1,
+            UF2_FLAG_FAMILY_ID,
+            address + (i * UF2_PAYLOAD_LEN) as u32,
+            payload.len() as u32,
+            i as u32,
+            count,
+            RP2350_ABSOLUTE_FAMILY_ID,
+        ];
+        for (word, bytes) in header.iter().zip(block.as_chunks_mut::<4>().0) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        block[32..32 + payload.len()].copy_from_slice(payload);
+        block[508..].copy_from_slice(&UF2_MAGIC_END.to_le_bytes());
+        out.extend_from_slice(&block);
+    }
+    out
+}
+
+/// Writes an image of the config sector to `path`: a `.uf2` to copy onto the Pico in
+/// BOOTSEL mode, or anything else as the raw sector.
+pub fn write_config(path: &Path, config: &DeviceConfig) -> anyhow::Result<()> {
+    let sector = sector(config)?;
+    if path.extension().is_some_and(|ext| ext == "uf2") {
+        std::fs::write(path, uf2(&sector, FLASH_ADDRESS))?;
+        println!(
+            "Wrote {}, copy it onto the Pico in BOOTSEL mode",
+            path.display()
+        );
+    } else {
+        std::fs::write(path, sector)?;
+        println!(
+            "Wrote {}, flash it with\n  probe-rs download --chip RP235x --binary-format bin \
+             --base-address {FLASH_ADDRESS:#x} {}",
+            path.display(),
+            path.display()
+        );
+    }
+    Ok(())
+}
@@ -1,94 +1,558 @@
-use common::LogMessage;
-use std::{io::Read, time::Duration};
+mod clock;
+mod command;
+mod devices;
+mod export;
+mod flash;
+mod recording;
+mod session;
 
-fn connect() -> anyhow::Result<()> {
-    let address = "192.168.0.132:1234";
+use clap::{Args, Parser, Subcommand};
+use command::CommandSender;
+use common::config::{self, DeviceConfig, IpConfig};
+use common::{CommandMessage, Transport, framing};
+use export::{Exporter, Format};
+use recording::{Kind, Reader, Recorder};
+use session::Session;
+use std::{
+    io::{BufRead, ErrorKind, Read},
+    net::{Ipv4Addr, TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Sender for the current connection, `None` while disconnected.
+type SharedSender = Arc<Mutex<Option<CommandSender>>>;
+
+const DEFAULT_ADDRESS: &str = "192.168.0.132:1234";
+
+/// How long to listen for announcements when looking for a device to connect to.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Receives telemetry from the pendulum and logs it to rerun. Without a subcommand it
+/// runs `live` with the defaults.
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Streams telemetry from the device to rerun and accepts commands on stdin.
+    Live {
+        #[command(flatten)]
+        link: LinkArgs,
+        #[command(flatten)]
+        viewer: ViewerArgs,
+        /// Also writes every received frame to this raw log.
+        #[arg(long)]
+        record: Option<PathBuf>,
+        /// Also writes the controller and bench messages as tables to this directory.
+        #[arg(long)]
+        export: Option<PathBuf>,
+        /// Format of the tables written with `--export`.
+        #[arg(long, value_enum, default_value_t = Format::Csv)]
+        export_format: Format,
+    },
+    /// Writes every frame received from the device to a raw log, without a viewer.
+    Record {
+        #[command(flatten)]
+        link: LinkArgs,
+        /// The raw log to write.
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Plays a raw log back into rerun.
+    Replay {
+        /// A raw log written by `record` or `live --record`.
+        log: PathBuf,
+        /// How many times faster than recorded to play it back.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        #[command(flatten)]
+        viewer: ViewerArgs,
+    },
+    /// Lists the devices announcing themselves on the local network.
+    Discover {
+        /// Seconds to listen for announcements.
+        #[arg(long, default_value_t = 3.0)]
+        duration: f64,
+    },
+    /// Writes an image of the device's network config to flash onto it, so the
+    /// credentials never need to be in the firmware.
+    WriteConfig {
+        /// Network to join.
+        #[arg(long)]
+        ssid: String,
+        /// Prompted for without echo if not given, keeping it out of the shell history.
+        #[arg(long)]
+        passphrase: Option<String>,
+        /// Passphrase of the access point the device opens when it cannot join `ssid`.
+        /// Prompted for like `passphrase` if not given.
+        #[arg(long)]
+        ap_passphrase: Option<String>,
+        /// Static address with its prefix length, like `192.168.0.132/24`. The address
+        /// is taken from DHCP without it.
+        #[arg(long)]
+        static_ip: Option<String>,
+        #[arg(long, requires = "static_ip")]
+        gateway: Option<Ipv4Addr>,
+        /// Port of the telemetry server.
+        #[arg(long, default_value_t = config::DEFAULT_PORT)]
+        port: u16,
+        /// Name the device announces itself with.
+        #[arg(long, default_value = config::DEFAULT_NAME)]
+        name: String,
+        /// A `.uf2` to copy onto the Pico in BOOTSEL mode, or a raw image of the sector
+        /// for any other extension.
+        out: PathBuf,
+    },
+    /// Converts a raw log to a `.rrd` file, or to tables of the controller and bench
+    /// messages, as fast as possible.
+    Export {
+        /// A raw log written by `record` or `live --record`.
+        log: PathBuf,
+        /// The `.rrd` file to write, or with `--format` the directory for the tables.
+        out: PathBuf,
+        /// Writes tables in this format instead of an `.rrd` file.
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+        /// Name of the rerun recording.
+        #[arg(long, default_value = "companion")]
+        name: String,
+    },
+}
+
+/// How to reach the device.
+#[derive(Args)]
+struct LinkArgs {
+    /// Address of the device's TCP server.
+    #[arg(default_value = DEFAULT_ADDRESS)]
+    address: String,
+    /// Connects to the first device that announces itself on the local network instead
+    /// of `address`.
+    #[arg(long, conflicts_with = "address")]
+    discover: bool,
+    /// Connects to the discovered device with this name, implies `--discover`.
+    #[arg(long, conflicts_with = "address")]
+    device: Option<String>,
+    /// Connects to the device on its own access point, which it opens when it cannot
+    /// join its configured network. Join the host to the WPA2 network `pendulum` first,
+    /// with the passphrase given to `write-config --ap-passphrase`, or `pendulum-setup`
+    /// on a device without a config.
+    #[arg(long, conflicts_with_all = ["address", "discover", "device"])]
+    ap: bool,
+    /// Requests telemetry as UDP datagrams, keeping TCP for the hello and commands.
+    #[arg(long)]
+    udp: bool,
+    /// Seconds the telemetry may stay silent before the connection is given up.
+    #[arg(long, default_value_t = 3.0)]
+    timeout: f64,
+    /// Seconds to wait before reconnecting after the connection failed.
+    #[arg(long, default_value_t = 1.0)]
+    reconnect_delay: f64,
+}
+
+/// Where the rerun recording goes, a viewer already running locally by default.
+#[derive(Args)]
+struct ViewerArgs {
+    /// Starts a new viewer.
+    #[arg(long, conflicts_with_all = ["connect", "save"])]
+    spawn: bool,
+    /// gRPC URL of a viewer on another machine, like
+    /// `rerun+http://10.0.0.2:9876/proxy`.
+    #[arg(long, conflicts_with = "save")]
+    connect: Option<String>,
+    /// Writes an `.rrd` file instead of logging to a viewer.
+    #[arg(long)]
+    save: Option<PathBuf>,
+    /// Name of the rerun recording.
+    #[arg(long, default_value = "companion")]
+    name: String,
+}
+
+impl ViewerArgs {
+    fn recording(&self) -> anyhow::Result<rerun::RecordingStream> {
+        let builder = rerun::RecordingStreamBuilder::new(self.name.as_str());
+        let rec = if self.spawn {
+            builder.spawn()?
+        } else if let Some(url) = &self.connect {
+            builder.connect_grpc_opts(url.as_str(), rerun::default_flush_timeout())?
+        } else if let Some(path) = &self.save {
+            builder.save(path)?
+        } else {
+            builder.connect_grpc()?
+        };
+        Ok(rec)
+    }
+}
+
+/// How long each socket is waited on before polling the other one in UDP mode.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Time between pings, which keep the device clock related to the host's, see `clock`.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Receives telemetry until the connection fails. With `udp` the telemetry is requested
+/// as datagrams, which may be lost or reordered but never stall the stream, while the
+/// TCP connection carries the hello and the commands. The device is pinged now and then
+/// to compare clocks. Every frame is also written to `recorder`, and every message to
+/// `exporter`, if given.
+fn connect(
+    link: &LinkArgs,
+    rec: &rerun::RecordingStream,
+    sender: &SharedSender,
+    recorder: Option<&mut Recorder>,
+    exporter: Option<&mut Exporter>,
+) -> anyhow::Result<()> {
+    let udp = link.udp;
+    let address = if link.ap {
+        let [a, b, c, d] = config::AP_ADDRESS;
+        format!("{a}.{b}.{c}.{d}:{}", config::DEFAULT_PORT)
+    } else if link.discover || link.device.is_some() {
+        println!("Looking for devices...");
+        let device = devices::find(link.device.as_deref(), DISCOVERY_TIMEOUT)?;
+        println!("Found {}", device.name);
+        device.address.to_string()
+    } else {
+        link.address.clone()
+    };
     println!("Connecting to {address:?}");
-    let mut connection = std::net::TcpStream::connect(address)?;
+    let mut connection = TcpStream::connect(&address)?;
     println!("Connected!");
+    let peer = connection.peer_addr()?;
+    *sender.lock().unwrap() = Some(CommandSender::new(connection.try_clone()?));
 
-    let rec = rerun::RecordingStreamBuilder::new("companion")
-        .connect_grpc()
-        .unwrap();
+    let mut session = Session::new(rec.clone(), recorder, exporter);
 
-    let mut size_bytes = [0_u8; 2];
-    let mut msg_buffer = [0_u8; 128];
-    connection.set_read_timeout(Some(std::time::Duration::from_millis(3000)))?;
+    let datagrams = if udp {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        connection.set_read_timeout(Some(POLL_INTERVAL))?;
+        Some(socket)
+    } else {
+        connection.set_read_timeout(Some(Duration::from_secs_f64(link.timeout)))?;
+        None
+    };
+    let mut subscribed = false;
+    let mut last_ping: Option<Instant> = None;
+    // Over UDP a device that went away is only noticed by the lack of datagrams.
+    let mut last_datagram = Instant::now();
 
+    let mut decoder = framing::Decoder::<{ framing::MAX_FRAME_LEN }>::new();
+    let mut read_buffer = [0_u8; 2048];
     loop {
-        connection.read_exact(&mut size_bytes)?;
-        let n = u16::from_be_bytes(size_bytes);
-        if n as usize > msg_buffer.len() {
-            continue;
+        match connection.read(&mut read_buffer) {
+            Ok(0) => anyhow::bail!("connection closed"),
+            Ok(n) => {
+                for &byte in &read_buffer[..n] {
+                    if let Some(frame) = decoder.push(byte) {
+                        session.handle(frame, clock::unix_time_us())?;
+                    }
+                }
+            }
+            Err(e) if udp && is_timeout(&e) => {}
+            Err(e) => return Err(e.into()),
         }
 
-        connection.read_exact(&mut msg_buffer[..n as usize])?;
-
-        let (msg, _) = bincode::serde::decode_from_slice::<LogMessage, _>(
-            &msg_buffer[..n as usize],
-            bincode::config::standard(),
-        )?;
-
-        match msg {
-            LogMessage::Controller(msg) => {
-                let time_ms = msg.time_ms;
-
-                rec.set_time("sample_time", Duration::from_millis(time_ms));
-
-                rec.log("control", &rerun::Scalars::single(msg.control as f64))
-                    .unwrap();
-                rec.log(
-                    "sensor_velocity",
-                    &rerun::Scalars::single(msg.sensor_wheel_velocity as f64),
-                )
-                .unwrap();
-                rec.log(
-                    "wheel_velocity",
-                    &rerun::Scalars::single(msg.wheel_velocity as f64),
-                )
-                .unwrap();
-                rec.log(
-                    "sensor_pend_angle",
-                    &rerun::Scalars::single(msg.sensor_pend_angle as f64),
-                )
-                .unwrap();
-                rec.log("pend_angle", &rerun::Scalars::single(msg.pend_angle as f64))
-                    .unwrap();
-                rec.log(
-                    "pend_velocity",
-                    &rerun::Scalars::single(msg.pend_velocity as f64),
-                )
-                .unwrap();
+        if session.greeted() && last_ping.is_none_or(|ping| ping.elapsed() >= PING_INTERVAL) {
+            if let Some(sender) = sender.lock().unwrap().as_mut() {
+                let host_time_us = clock::unix_time_us();
+                sender.send(CommandMessage::Ping { host_time_us })?;
             }
-            LogMessage::Bench(msg) => {
-                let time_ms = msg.time_ms;
-                rec.set_time("sample_time", Duration::from_millis(time_ms));
-                rec.log("control", &rerun::Scalars::single(msg.control as f64))
-                    .unwrap();
-                rec.log(
-                    "wheel_velocity",
-                    &rerun::Scalars::single(msg.signed_rot_speed as f64),
-                )
-                .unwrap();
-                rec.log(
-                    "abs_wheel_velocity",
-                    &rerun::Scalars::single(msg.abs_rot_speed as f64),
-                )
-                .unwrap();
+            last_ping = Some(Instant::now());
+        }
+
+        let Some(socket) = &datagrams else {
+            continue;
+        };
+        if session.greeted() && !subscribed {
+            let port = socket.local_addr()?.port();
+            if let Some(sender) = sender.lock().unwrap().as_mut() {
+                sender.send(CommandMessage::SetTransport(Transport::Udp { port }))?;
+            }
+            println!("Requested telemetry over UDP port {port}");
+            subscribed = true;
+            last_datagram = Instant::now();
+        }
+        // Takes every datagram waiting, there may be many for each poll of the TCP
+        // stream.
+        loop {
+            match socket.recv_from(&mut read_buffer) {
+                Ok((n, from)) if from.ip() == peer.ip() => {
+                    last_datagram = Instant::now();
+                    // Every datagram holds whole frames.
+                    let mut decoder = framing::Decoder::<{ framing::MAX_FRAME_LEN }>::new();
+                    for &byte in &read_buffer[..n] {
+                        if let Some(frame) = decoder.push(byte) {
+                            session.handle(frame, clock::unix_time_us())?;
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if is_timeout(&e) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if subscribed && last_datagram.elapsed().as_secs_f64() > link.timeout {
+            anyhow::bail!("no telemetry for {} s", link.timeout);
+        }
+    }
+}
+
+/// Parses an address like `192.168.0.132/24`.
+fn parse_cidr(cidr: &str) -> anyhow::Result<([u8; 4], u8)> {
+    let (address, prefix_len) = cidr
+        .split_once('/')
+        .ok_or_else(|| anyhow::anyhow!("expected an address like 192.168.0.132/24"))?;
+    let address: Ipv4Addr = address.parse()?;
+    let prefix_len: u8 = prefix_len.parse()?;
+    anyhow::ensure!(prefix_len <= 32, "prefix length {prefix_len} is over 32");
+    Ok((address.octets(), prefix_len))
+}
+
+/// Checks that `passphrase`, of the network called `what`, is one WPA2 accepts: 8 to 63
+/// characters, or the key itself as 64 hex digits.
+fn check_passphrase(what: &str, passphrase: &str) -> anyhow::Result<()> {
+    let hex_key = passphrase.len() == 64 && passphrase.bytes().all(|b| b.is_ascii_hexdigit());
+    anyhow::ensure!(
+        (8..=63).contains(&passphrase.len()) || hex_key,
+        "the {what} must be 8 to 63 characters, or 64 hex digits"
+    );
+    Ok(())
+}
+
+/// Checks that each setting fits the config record.
+fn device_config(
+    ssid: &str,
+    passphrase: &str,
+    ap_passphrase: &str,
+    static_ip: Option<&str>,
+    gateway: Option<Ipv4Addr>,
+    port: u16,
+    name: &str,
+) -> anyhow::Result<DeviceConfig> {
+    let ip = match static_ip {
+        Some(cidr) => {
+            let (address, prefix_len) = parse_cidr(cidr)?;
+            IpConfig::Static {
+                address,
+                prefix_len,
+                gateway: gateway.map(|gateway| gateway.octets()),
             }
-            LogMessage::Alive => {}
         }
+        None => IpConfig::Dhcp,
+    };
+    check_passphrase("passphrase", passphrase)?;
+    check_passphrase("access point passphrase", ap_passphrase)?;
+    Ok(DeviceConfig {
+        ssid: ssid
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("the SSID is longer than 32 bytes"))?,
+        passphrase: passphrase
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("the passphrase is longer than 64 bytes"))?,
+        ap_passphrase: ap_passphrase
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("the access point passphrase is longer than 64 bytes"))?,
+        ip,
+        port,
+        name: name
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("the name is longer than 32 bytes"))?,
+    })
+}
+
+/// Feeds a log written by `Recorder` through the same path as live frames, `speed`
+/// times faster than it was recorded.
+fn replay(
+    path: &Path,
+    speed: f64,
+    rec: &rerun::RecordingStream,
+    mut exporter: Option<Exporter>,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(speed > 0.0, "the speed must be positive");
+    let mut reader = Reader::open(path)?;
+    let mut session = Session::new(rec.clone(), None, exporter.as_mut());
+    let started = Instant::now();
+    let mut first_us = None;
+    let mut frames = 0_u64;
+    while let Some(record) = reader.next_record()? {
+        let first_us = *first_us.get_or_insert(record.time_us);
+        let due = Duration::from_micros(record.time_us.saturating_sub(first_us)).div_f64(speed);
+        if let Some(wait) = due.checked_sub(started.elapsed()) {
+            std::thread::sleep(wait);
+        }
+        if record.kind == Kind::Hello && session.greeted() {
+            // The receiver reconnected while recording.
+            session.reset();
+        }
+        session.handle(Ok(&record.payload), record.time_us)?;
+        frames += 1;
     }
+    drop(session);
+    if let Some(exporter) = exporter.as_mut() {
+        exporter.close()?;
+    }
+    println!("Replayed {frames} frames from {}", path.display());
+    Ok(())
+}
 
-    // static gain: 2050 / 0.125
-    // Time constant = 0.56
+/// Reads commands from stdin and sends them over the current connection.
+fn command_prompt(sender: SharedSender) {
+    println!("{}", command::HELP);
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let command = match command::parse(&line) {
+            Ok(command) => command,
+            Err(e) => {
+                println!("{e}\n{}", command::HELP);
+                continue;
+            }
+        };
+        match sender.lock().unwrap().as_mut() {
+            Some(sender) => match sender.send(command.clone()) {
+                Ok(id) => println!("Sent #{id}: {command:?}"),
+                Err(e) => println!("Failed to send {command:?}: {e}"),
+            },
+            None => println!("Not connected"),
+        }
+    }
 }
 
-fn main() {
+/// Receives from the device until interrupted, reconnecting whenever the connection
+/// fails. Commands are read from stdin if `commands` is set.
+fn run(
+    link: &LinkArgs,
+    rec: &rerun::RecordingStream,
+    mut recorder: Option<Recorder>,
+    mut exporter: Option<Exporter>,
+    commands: bool,
+) -> anyhow::Result<()> {
+    let sender = SharedSender::default();
+    if commands {
+        std::thread::spawn({
+            let sender = sender.clone();
+            move || command_prompt(sender)
+        });
+    }
     loop {
-        if let Err(e) = connect() {
+        if let Err(e) = connect(link, rec, &sender, recorder.as_mut(), exporter.as_mut()) {
             println!("Error: {e:?}",);
         }
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+        if let Some(exporter) = exporter.as_mut() {
+            exporter.close()?;
+        }
+        *sender.lock().unwrap() = None;
+        std::thread::sleep(Duration::from_secs_f64(link.reconnect_delay));
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let command = match Cli::parse().command {
+        Some(command) => command,
+        None => Cli::parse_from(["receiver", "live"]).command.unwrap(),
+    };
+    match command {
+        Command::Live {
+            link,
+            viewer,
+            record,
+            export,
+            export_format,
+        } => {
+            let recorder = record.as_deref().map(Recorder::create).transpose()?;
+            let exporter = export
+                .map(|dir| Exporter::create(&dir, export_format))
+                .transpose()?;
+            run(&link, &viewer.recording()?, recorder, exporter, true)
+        }
+        Command::Record { link, out } => {
+            let recorder = Recorder::create(&out)?;
+            run(
+                &link,
+                &rerun::RecordingStream::disabled(),
+                Some(recorder),
+                None,
+                false,
+            )
+        }
+        Command::Replay { log, speed, viewer } => replay(&log, speed, &viewer.recording()?, None),
+        Command::Discover { duration } => {
+            let devices = devices::list(Duration::from_secs_f64(duration))?;
+            if devices.is_empty() {
+                println!("No devices found");
+            }
+            for device in devices {
+                let note = if device.is_compatible() {
+                    ""
+                } else {
+                    " (incompatible protocol version)"
+                };
+                println!("{}  {}{note}", device.address, device.name);
+            }
+            Ok(())
+        }
+        Command::WriteConfig {
+            ssid,
+            passphrase,
+            ap_passphrase,
+            static_ip,
+            gateway,
+            port,
+            name,
+            out,
+        } => {
+            let passphrase = match passphrase {
+                Some(passphrase) => passphrase,
+                None => rpassword::prompt_password(format!("Passphrase for {ssid:?}: "))?,
+            };
+            let ap_passphrase = match ap_passphrase {
+                Some(passphrase) => passphrase,
+                None => rpassword::prompt_password(format!(
+                    "Passphrase for the access point {:?}: ",
+                    config::AP_SSID
+                ))?,
+            };
+            let config = device_config(
+                &ssid,
+                &passphrase,
+                &ap_passphrase,
+                static_ip.as_deref(),
+                gateway,
+                port,
+                &name,
+            )?;
+            flash::write_config(&out, &config)
+        }
+        Command::Export {
+            log,
+            out,
+            format,
+            name,
+        } => {
+            match format {
+                Some(format) => replay(
+                    &log,
+                    f64::INFINITY,
+                    &rerun::RecordingStream::disabled(),
+                    Some(Exporter::create(&out, format)?),
+                )?,
+                None => {
+                    let rec = rerun::RecordingStreamBuilder::new(name.as_str()).save(&out)?;
+                    replay(&log, f64::INFINITY, &rec, None)?;
+                }
+            }
+            println!("Wrote {}", out.display());
+            Ok(())
+        }
     }
 }
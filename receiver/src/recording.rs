@@ -0,0 +1,182 @@
+//! Raw log of the frames received from the firmware.
+//!
+//! A log starts with `MAGIC` followed by one record per frame:
+//!
+//! | bytes | content                                                 |
+//! |-------|---------------------------------------------------------|
+//! | 8     | host time of arrival, microseconds since the Unix epoch |
+//! | 1     | `Kind`                                                  |
+//! | 2     | payload length                                          |
+//! | n     | the frame's payload, with the CRC checked and removed   |
+//!
+//! All integers are little-endian. Each connection starts with a `Kind::Hello` record,
+//! so a log can hold several connections, and a truncated last record is ignored.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Identifies the file format and its version.
+pub const MAGIC: &[u8; 8] = b"PENDLOG1";
+
+/// How long a record may stay buffered before it is written to the file.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// The `HelloMessage` that starts a connection.
+    Hello = 0,
+    /// An `Envelope`.
+    Message = 1,
+}
+
+pub struct Record {
+    pub time_us: u64,
+    pub kind: Kind,
+    pub payload: Vec<u8>,
+}
+
+pub struct Recorder {
+    out: BufWriter<File>,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        Ok(Recorder {
+            out,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Appends a frame received at `time_us`, see `clock::unix_time_us`.
+    pub fn record(&mut self, kind: Kind, time_us: u64, payload: &[u8]) -> anyhow::Result<()> {
+        let len = u16::try_from(payload.len())?;
+        self.out.write_all(&time_us.to_le_bytes())?;
+        self.out.write_all(&[kind as u8])?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(payload)?;
+        // Keeps at most a second of data at risk if the receiver is killed.
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.out.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+pub struct Reader {
+    input: BufReader<File>,
+}
+
+impl Reader {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0_u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            anyhow::bail!("{} is not a telemetry log", path.display());
+        }
+        Ok(Reader { input })
+    }
+
+    /// Reads the next record, `None` at the end of the log.
+    pub fn next_record(&mut self) -> anyhow::Result<Option<Record>> {
+        let mut header = [0_u8; 11];
+        let mut payload = Vec::new();
+        let read = self.input.read_exact(&mut header).and_then(|()| {
+            payload.resize(u16::from_le_bytes([header[9], header[10]]) as usize, 0);
+            self.input.read_exact(&mut payload)
+        });
+        match read {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let kind = match header[8] {
+            0 => Kind::Hello,
+            1 => Kind::Message,
+            other => anyhow::bail!("unknown record kind {other}"),
+        };
+        Ok(Some(Record {
+            time_us: u64::from_le_bytes(header[..8].try_into().unwrap()),
+            kind,
+            payload,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("recording-{}-{name}.log", std::process::id()))
+    }
+
+    fn write_log(path: &Path) {
+        let mut recorder = Recorder::create(path).unwrap();
+        recorder.record(Kind::Hello, 1, b"hello").unwrap();
+        recorder.record(Kind::Message, 2, b"first").unwrap();
+        recorder.record(Kind::Message, u64::MAX, &[]).unwrap();
+        recorder.record(Kind::Hello, 3, &[0xFF; 300]).unwrap();
+    }
+
+    fn read_log(path: &Path) -> Vec<(u64, Kind, Vec<u8>)> {
+        let mut reader = Reader::open(path).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            records.push((record.time_us, record.kind, record.payload));
+        }
+        records
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let path = temp_path("round-trip");
+        write_log(&path);
+        let records = read_log(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            records,
+            [
+                (1, Kind::Hello, b"hello".to_vec()),
+                (2, Kind::Message, b"first".to_vec()),
+                (u64::MAX, Kind::Message, Vec::new()),
+                (3, Kind::Hello, vec![0xFF; 300]),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_last_record_is_ignored() {
+        let path = temp_path("truncated");
+        write_log(&path);
+        let len = std::fs::metadata(&path).unwrap().len();
+        for cut in [1, 300, 305] {
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_len(len - cut)
+                .unwrap();
+            assert_eq!(read_log(&path).len(), 3, "cut {cut} bytes");
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = temp_path("bad-magic");
+        std::fs::write(&path, b"PENDLOG0 and then some").unwrap();
+        assert!(Reader::open(&path).is_err());
+        std::fs::write(&path, b"PEND").unwrap();
+        assert!(Reader::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,402 @@
+use crate::clock::ClockSync;
+use crate::export::Exporter;
+use crate::recording::{Kind, Recorder};
+use common::framing::FrameError;
+use common::{Envelope, HelloMessage, LogLevel, LogMessage, PROTOCOL_VERSION, framing};
+use std::time::{Duration, Instant};
+
+/// How often the drop rate is logged.
+const DROP_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// A message this far behind the newest one is taken to have been reordered on the way
+/// rather than as a sign that the device restarted.
+const REORDER_WINDOW: u32 = 1000;
+
+/// Follows the sequence numbers of received messages, so a gap in the plots can be told
+/// apart from something the device actually did.
+struct LinkStats {
+    next_seq: Option<u32>,
+    device_dropped: u32,
+    /// Messages missing from the sequence since connecting.
+    lost: u64,
+    /// Messages that arrived after a newer one since connecting.
+    reordered: u64,
+    window_start: Instant,
+    window_received: u64,
+    window_lost: u64,
+}
+
+impl LinkStats {
+    fn new() -> Self {
+        LinkStats {
+            next_seq: None,
+            device_dropped: 0,
+            lost: 0,
+            reordered: 0,
+            window_start: Instant::now(),
+            window_received: 0,
+            window_lost: 0,
+        }
+    }
+
+    /// Accounts for a received message and logs gaps and drop rates to rerun.
+    fn update(&mut self, rec: &rerun::RecordingStream, envelope: &Envelope) {
+        self.window_received += 1;
+        let expected = *self.next_seq.get_or_insert(envelope.seq);
+        let gap = envelope.seq.wrapping_sub(expected);
+        let behind = expected.wrapping_sub(envelope.seq);
+
+        if gap < u32::MAX / 2 {
+            if gap > 0 {
+                self.log_gap(rec, envelope, gap);
+            }
+            self.next_seq = Some(envelope.seq.wrapping_add(1));
+            self.device_dropped = envelope.dropped;
+        } else if behind <= REORDER_WINDOW {
+            // It was counted as lost when a newer message arrived first.
+            self.reordered += 1;
+            self.lost = self.lost.saturating_sub(1);
+            self.window_lost = self.window_lost.saturating_sub(1);
+        } else {
+            println!("Sequence number went back, the device restarted");
+            self.next_seq = Some(envelope.seq.wrapping_add(1));
+            self.device_dropped = envelope.dropped;
+        }
+
+        if self.window_start.elapsed() >= DROP_RATE_WINDOW {
+            self.log_window(rec);
+        }
+    }
+
+    fn log_gap(&mut self, rec: &rerun::RecordingStream, envelope: &Envelope, gap: u32) {
+        self.lost += gap as u64;
+        self.window_lost += gap as u64;
+        let device_dropped = envelope.dropped.wrapping_sub(self.device_dropped);
+        let text = format!(
+            "Lost {gap} messages before #{}, {device_dropped} dropped on the device",
+            envelope.seq
+        );
+        println!("{text}");
+        rec.log(
+            "transport/gap",
+            &rerun::TextLog::new(text).with_level(rerun::TextLogLevel::WARN),
+        )
+        .unwrap();
+    }
+
+    fn log_window(&mut self, rec: &rerun::RecordingStream) {
+        let expected = self.window_received + self.window_lost;
+        rec.log(
+            "transport/drop_rate",
+            &rerun::Scalars::single(self.window_lost as f64 / expected as f64),
+        )
+        .unwrap();
+        rec.log("transport/lost", &rerun::Scalars::single(self.lost as f64))
+            .unwrap();
+        rec.log(
+            "transport/reordered",
+            &rerun::Scalars::single(self.reordered as f64),
+        )
+        .unwrap();
+        rec.log(
+            "transport/device_dropped",
+            &rerun::Scalars::single(self.device_dropped as f64),
+        )
+        .unwrap();
+        self.window_start = Instant::now();
+        self.window_received = 0;
+        self.window_lost = 0;
+    }
+}
+
+/// Everything received over one connection, whichever socket it arrived on.
+pub struct Session<'a> {
+    rec: rerun::RecordingStream,
+    recorder: Option<&'a mut Recorder>,
+    exporter: Option<&'a mut Exporter>,
+    stats: LinkStats,
+    clock: ClockSync,
+    corrupt_frames: u64,
+    greeted: bool,
+}
+
+impl<'a> Session<'a> {
+    /// Logs to `rec`, archives every intact frame with `recorder` and tabulates the
+    /// messages with `exporter` if given.
+    pub fn new(
+        rec: rerun::RecordingStream,
+        recorder: Option<&'a mut Recorder>,
+        exporter: Option<&'a mut Exporter>,
+    ) -> Self {
+        Session {
+            rec,
+            recorder,
+            exporter,
+            stats: LinkStats::new(),
+            clock: ClockSync::new(),
+            corrupt_frames: 0,
+            greeted: false,
+        }
+    }
+
+    /// Starts over as if on a new connection, expecting a hello first.
+    pub fn reset(&mut self) {
+        self.stats = LinkStats::new();
+        self.clock = ClockSync::new();
+        self.greeted = false;
+    }
+
+    /// Whether the hello has been received.
+    pub fn greeted(&self) -> bool {
+        self.greeted
+    }
+
+    /// Handles a frame received at `time_us` host time, see `clock::unix_time_us`. The
+    /// first frame must be the firmware's hello.
+    pub fn handle(&mut self, frame: Result<&[u8], FrameError>, time_us: u64) -> anyhow::Result<()> {
+        if let (Ok(payload), Some(recorder)) = (frame, self.recorder.as_mut()) {
+            let kind = if self.greeted {
+                Kind::Message
+            } else {
+                Kind::Hello
+            };
+            recorder.record(kind, time_us, payload)?;
+        }
+        let msg = match frame {
+            Ok(payload) if !self.greeted => {
+                greet(&self.rec, payload)?;
+                self.greeted = true;
+                return Ok(());
+            }
+            frame => frame.and_then(framing::decode_message::<Envelope>),
+        };
+        match msg {
+            Ok(envelope) => {
+                if let LogMessage::Pong(pong) = &envelope.msg {
+                    self.clock.update(pong, time_us);
+                }
+                log_message(&self.rec, &self.clock, &envelope.msg);
+                if let Some(exporter) = self.exporter.as_mut() {
+                    exporter.push(&envelope.msg, &self.clock, time_us)?;
+                }
+                self.stats.update(&self.rec, &envelope);
+            }
+            Err(e) => {
+                self.corrupt_frames += 1;
+                println!(
+                    "Skipped corrupt frame ({e:?}), {} so far",
+                    self.corrupt_frames
+                );
+                self.rec
+                    .log(
+                        "transport/corrupt_frames",
+                        &rerun::Scalars::single(self.corrupt_frames as f64),
+                    )
+                    .unwrap();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the firmware speaks our protocol and records what it is running.
+fn greet(rec: &rerun::RecordingStream, payload: &[u8]) -> anyhow::Result<()> {
+    let version = HelloMessage::peek_version(payload)
+        .map_err(|e| anyhow::anyhow!("expected a hello message: {e:?}"))?;
+    if version != PROTOCOL_VERSION {
+        anyhow::bail!(
+            "firmware speaks protocol version {version}, this receiver version {PROTOCOL_VERSION}"
+        );
+    }
+    let hello: HelloMessage = framing::decode_message(payload)
+        .map_err(|e| anyhow::anyhow!("invalid hello message: {e:?}"))?;
+    println!("Talking to {} built from {}", hello.binary, hello.build_id);
+
+    let model = hello.model;
+    let text = format!(
+        "binary: {}\n\
+         build: {}\n\
+         protocol version: {}\n\
+         sample time: {} ms\n\
+         gains: {:?}\n\
+         wheel time constant: {}\n\
+         wheel static gain: {}\n\
+         inertia ratio: {}\n\
+         radius: {}\n\
+         gravity: {}",
+        hello.binary,
+        hello.build_id,
+        hello.protocol_version,
+        hello.sample_time_ms,
+        hello.gains,
+        model.wheel_time_constant,
+        model.wheel_static_gain,
+        model.inertia_ratio,
+        model.radius,
+        model.gravity,
+    );
+    rec.log_static("firmware", &rerun::TextDocument::new(text))
+        .unwrap();
+    Ok(())
+}
+
+/// Sets the device's `sample_time`, and the same instant on the host's clock as
+/// `host_time` once the clocks have been compared, which lines the plots up with
+/// anything else recorded on the host, like a video.
+fn set_time(rec: &rerun::RecordingStream, clock: &ClockSync, time_us: u64) {
+    rec.set_time("sample_time", Duration::from_micros(time_us));
+    if let Some(host_us) = clock.host_time_us(time_us) {
+        rec.set_time(
+            "host_time",
+            rerun::TimeCell::from_timestamp_nanos_since_epoch(host_us * 1000),
+        );
+    }
+}
+
+fn log_message(rec: &rerun::RecordingStream, clock: &ClockSync, msg: &LogMessage) {
+    match msg {
+        LogMessage::Controller(msg) => {
+            let time_ms = msg.time_ms;
+
+            set_time(rec, clock, time_ms * 1000);
+
+            rec.log("state", &rerun::Scalars::single(msg.state as u8 as f64))
+                .unwrap();
+            rec.log("control", &rerun::Scalars::single(msg.control as f64))
+                .unwrap();
+            rec.log(
+                "sensor_velocity",
+                &rerun::Scalars::single(msg.sensor_wheel_velocity as f64),
+            )
+            .unwrap();
+            rec.log(
+                "wheel_velocity",
+                &rerun::Scalars::single(msg.wheel_velocity as f64),
+            )
+            .unwrap();
+            rec.log(
+                "sensor_pend_angle",
+                &rerun::Scalars::single(msg.sensor_pend_angle as f64),
+            )
+            .unwrap();
+            rec.log("pend_angle", &rerun::Scalars::single(msg.pend_angle as f64))
+                .unwrap();
+            rec.log(
+                "pend_velocity",
+                &rerun::Scalars::single(msg.pend_velocity as f64),
+            )
+            .unwrap();
+        }
+        LogMessage::Bench(msg) => {
+            let time_ms = msg.time_ms;
+            set_time(rec, clock, time_ms * 1000);
+            rec.log("control", &rerun::Scalars::single(msg.control as f64))
+                .unwrap();
+            rec.log(
+                "wheel_velocity",
+                &rerun::Scalars::single(msg.signed_rot_speed as f64),
+            )
+            .unwrap();
+            rec.log(
+                "abs_wheel_velocity",
+                &rerun::Scalars::single(msg.abs_rot_speed as f64),
+            )
+            .unwrap();
+        }
+        LogMessage::Fault(msg) => {
+            set_time(rec, clock, msg.time_ms * 1000);
+            rec.log(
+                "fault",
+                &rerun::TextLog::new(format!("Motor cut: {:?}", msg.fault))
+                    .with_level(rerun::TextLogLevel::ERROR),
+            )
+            .unwrap();
+        }
+        LogMessage::Timing(msg) => {
+            set_time(rec, clock, msg.time_ms * 1000);
+            for (name, summary) in [
+                ("jitter", msg.jitter),
+                ("compute", msg.compute),
+                ("encoder", msg.encoder),
+            ] {
+                rec.log(
+                    format!("timing/{name}/min_us"),
+                    &rerun::Scalars::single(summary.min_us as f64),
+                )
+                .unwrap();
+                rec.log(
+                    format!("timing/{name}/max_us"),
+                    &rerun::Scalars::single(summary.max_us as f64),
+                )
+                .unwrap();
+                rec.log(
+                    format!("timing/{name}/mean_us"),
+                    &rerun::Scalars::single(summary.mean_us as f64),
+                )
+                .unwrap();
+            }
+            rec.log(
+                "timing/overruns",
+                &rerun::Scalars::single(msg.overruns as f64),
+            )
+            .unwrap();
+        }
+        LogMessage::Alive => {}
+        LogMessage::Covariance(msg) => {
+            set_time(rec, clock, msg.time_ms * 1000);
+            for row in 0..3 {
+                for column in row..3 {
+                    rec.log(
+                        format!("covariance/p{row}{column}"),
+                        &rerun::Scalars::single(msg.p[row][column] as f64),
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        LogMessage::Status(msg) => {
+            println!(
+                "Status: {:?} {:?}, output {:.2}, gains {:?}, fault {:?}",
+                msg.mode, msg.state, msg.output, msg.gains, msg.fault
+            );
+        }
+        LogMessage::Pong(msg) => {
+            set_time(rec, clock, msg.received_us);
+            if let Some(exchange) = clock.latest() {
+                rec.log(
+                    "clock/rtt_ms",
+                    &rerun::Scalars::single(exchange.rtt_us as f64 * 1e-3),
+                )
+                .unwrap();
+            }
+        }
+        LogMessage::Text(msg) => {
+            println!("Device: {}", msg.text);
+            set_time(rec, clock, msg.time_ms * 1000);
+            let level = match msg.level {
+                LogLevel::Debug => rerun::TextLogLevel::DEBUG,
+                LogLevel::Info => rerun::TextLogLevel::INFO,
+                LogLevel::Warn => rerun::TextLogLevel::WARN,
+                LogLevel::Error => rerun::TextLogLevel::ERROR,
+            };
+            rec.log(
+                "firmware/log",
+                &rerun::TextLog::new(msg.text.as_str()).with_level(level),
+            )
+            .unwrap();
+        }
+        LogMessage::Ack(msg) => {
+            let text = match msg.result {
+                Ok(()) => format!("Command #{} done", msg.id),
+                Err(e) => format!("Command #{} rejected: {e:?}", msg.id),
+            };
+            println!("{text}");
+            let level = match msg.result {
+                Ok(()) => rerun::TextLogLevel::INFO,
+                Err(_) => rerun::TextLogLevel::WARN,
+            };
+            rec.log("commands", &rerun::TextLog::new(text).with_level(level))
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+use common::control::{BalancingState, DEFAULT_GAINS};
+use common::discovery::{self, Announcement};
+use common::{
+    CommandMessage, CommandRequest, ControllerMessage, Envelope, HelloMessage, LogMessage,
+    PongMessage, SAMPLE_TIME_MS, Transport,
+};
+use sim::plant::{PlantParams, PlantState};
+use sim::rig::RigConfig;
+use sim::{Simulation, read_frame, write_frame};
+
+/// Listens for the receiver like the firmware telemetry server does and streams log
+/// messages to it, so the receiver can be developed without a Pico.
+#[derive(Parser)]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1:1234")]
+    listen: String,
+    /// Name announced to receivers looking for devices.
+    #[arg(long, default_value = "fake_firmware")]
+    name: String,
+    /// Where the announcements are sent, the local network's broadcast address like
+    /// the firmware does by default.
+    #[arg(long, default_value = "255.255.255.255")]
+    announce_to: String,
+    #[command(subcommand)]
+    source: Source,
+}
+
+#[derive(Subcommand, Clone)]
+enum Source {
+    /// Sine waves on every controller field.
+    Synthetic,
+    /// The balancing controller running against the simulated rig in real time.
+    Sim {
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        /// Encoder noise standard deviation in radians.
+        #[arg(long, default_value_t = 0.001)]
+        noise: f64,
+    },
+    /// A file written by the `sim` binary, paced by the message timestamps. Sequence
+    /// numbers are sent as recorded.
+    Replay {
+        file: PathBuf,
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+}
+
+fn synthetic() -> impl Iterator<Item = Envelope> {
+    (0_u32..).map(|seq| {
+        let i = seq as u64;
+        let time_ms = i * SAMPLE_TIME_MS as u64;
+        let t = time_ms as f32 * 1e-3;
+        let angle = t.sin();
+        let msg = LogMessage::Controller(ControllerMessage {
+            time_ms,
+            state: BalancingState::Balancing,
+            control: 0.5 * (3.0 * t).sin(),
+            sensor_pend_angle: angle + 0.01 * (50.0 * t).sin(),
+            sensor_wheel_velocity: f32::NAN,
+            pend_angle: angle,
+            pend_velocity: t.cos(),
+            wheel_velocity: 100.0 * (0.5 * t).sin(),
+        });
+        Envelope {
+            seq,
+            dropped: 0,
+            msg,
+        }
+    })
+}
+
+type Messages = Box<dyn Iterator<Item = Envelope>>;
+
+/// Returns the greeting and the messages that follow it.
+fn messages(source: &Source) -> anyhow::Result<(HelloMessage, Messages)> {
+    Ok(match source {
+        Source::Synthetic => {
+            let hello =
+                HelloMessage::new("fake_firmware", env!("CARGO_PKG_VERSION"), DEFAULT_GAINS);
+            (hello, Box::new(synthetic()))
+        }
+        Source::Sim { seed, noise } => {
+            let config = RigConfig {
+                angle_noise: *noise,
+                seed: *seed,
+                ..Default::default()
+            };
+            let initial = PlantState {
+                pend_angle: 3.1,
+                ..Default::default()
+            };
+            let mut sim = Simulation::new(PlantParams::default(), initial, config)?;
+            let hello = sim.hello("fake_firmware");
+            let messages = std::iter::repeat_with(move || sim.step_messages()).flatten();
+            (hello, Box::new(messages))
+        }
+        Source::Replay { file, .. } => {
+            let mut reader = BufReader::new(File::open(file)?);
+            let hello = read_frame(&mut reader)?
+                .ok_or_else(|| anyhow::anyhow!("{} is empty", file.display()))?;
+            let messages = std::iter::from_fn(move || match read_frame(&mut reader) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    println!("Stopping replay: {e:?}");
+                    None
+                }
+            });
+            (hello, Box::new(messages))
+        }
+    })
+}
+
+fn time_ms(msg: &LogMessage) -> Option<u64> {
+    match msg {
+        LogMessage::Controller(msg) => Some(msg.time_ms),
+        LogMessage::Bench(msg) => Some(msg.time_ms),
+        LogMessage::Fault(msg) => Some(msg.time_ms),
+        LogMessage::Timing(msg) => Some(msg.time_ms),
+        LogMessage::Status(msg) => Some(msg.time_ms),
+        LogMessage::Covariance(msg) => Some(msg.time_ms),
+        LogMessage::Text(msg) => Some(msg.time_ms),
+        LogMessage::Alive | LogMessage::Ack(_) | LogMessage::Pong(_) => None,
+    }
+}
+
+/// Reads commands from the receiver until it disconnects. Only transport changes are
+/// carried out, and pings passed on to `pings` with the time they arrived.
+fn read_commands(
+    stream: TcpStream,
+    transport: Arc<Mutex<Transport>>,
+    pings: mpsc::Sender<(u64, Instant)>,
+) {
+    let mut reader = BufReader::new(stream);
+    while let Ok(Some(request)) = read_frame::<CommandRequest>(&mut reader) {
+        match request.command {
+            CommandMessage::SetTransport(new_transport) => {
+                println!("Streaming over {new_transport:?}");
+                *transport.lock().unwrap() = new_transport;
+            }
+            CommandMessage::Ping { host_time_us } => {
+                let _ = pings.send((host_time_us, Instant::now()));
+            }
+            command => println!("Ignoring {command:?}"),
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, udp: &UdpSocket, source: &Source) -> anyhow::Result<()> {
+    stream.set_nodelay(true)?;
+    let transport = Arc::new(Mutex::new(Transport::Tcp));
+    let (ping_sender, pings) = mpsc::channel();
+    std::thread::spawn({
+        let stream = stream.try_clone()?;
+        let transport = transport.clone();
+        move || read_commands(stream, transport, ping_sender)
+    });
+    let client = stream.peer_addr()?.ip();
+
+    let speed = match source {
+        Source::Replay { speed, .. } => *speed,
+        _ => 1.0,
+    };
+
+    let (hello, messages) = messages(source)?;
+    write_frame(&mut stream, &hello)?;
+
+    let mut send = |msg: &Envelope| -> anyhow::Result<()> {
+        let transport = *transport.lock().unwrap();
+        match transport {
+            Transport::Tcp => write_frame(&mut stream, msg)?,
+            Transport::Udp { port } => {
+                let mut datagram = Vec::new();
+                write_frame(&mut datagram, msg)?;
+                udp.send_to(&datagram, (client, port))?;
+            }
+        }
+        Ok(())
+    };
+
+    let start = Instant::now();
+    let mut first_time_ms = None;
+    // The device clock is the one of the source, running from its first timestamp.
+    let device_us = |at: Instant, first_time_ms: Option<u64>| {
+        let elapsed = at.duration_since(start).as_secs_f64() * speed;
+        first_time_ms.unwrap_or(0) * 1000 + (elapsed * 1e6) as u64
+    };
+    // Pongs are numbered in between the source's messages, which move up to make room.
+    let mut pongs = 0_u32;
+    for mut msg in messages {
+        if let Some(time_ms) = time_ms(&msg.msg) {
+            let first = *first_time_ms.get_or_insert(time_ms);
+            let offset = time_ms.saturating_sub(first) as f64 * 1e-3 / speed;
+            let due = start + Duration::from_secs_f64(offset);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        for (host_time_us, received) in pings.try_iter() {
+            let pong = PongMessage {
+                host_time_us,
+                received_us: device_us(received, first_time_ms),
+                sent_us: device_us(Instant::now(), first_time_ms),
+            };
+            send(&Envelope {
+                seq: msg.seq.wrapping_add(pongs),
+                dropped: msg.dropped,
+                msg: LogMessage::Pong(pong),
+            })?;
+            pongs += 1;
+        }
+        msg.seq = msg.seq.wrapping_add(pongs);
+        send(&msg)?;
+    }
+    Ok(())
+}
+
+/// Announces the server like the firmware does, see `common::discovery`.
+fn announce(name: &str, port: u16, to: &str) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.set_broadcast(true)?;
+    let mut datagram = [0_u8; discovery::MAX_DATAGRAM_LEN];
+    let n = Announcement::new(name, port)
+        .encode(&mut datagram)
+        .ok_or_else(|| anyhow::anyhow!("failed to encode announcement"))?;
+    loop {
+        socket.send_to(&datagram[..n], (to, discovery::PORT))?;
+        std::thread::sleep(Duration::from_millis(discovery::ANNOUNCE_INTERVAL_MS));
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    // Fails on bad settings before any client connects, each gets its own messages later.
+    let _ = messages(&args.source)?;
+    let listener = TcpListener::bind(&args.listen)?;
+    std::thread::spawn({
+        let name = args.name.clone();
+        let port = listener.local_addr()?.port();
+        let to = args.announce_to.clone();
+        move || {
+            if let Err(e) = announce(&name, port, &to) {
+                println!("Stopped announcing: {e}");
+            }
+        }
+    });
+
+    // Datagrams to every client come from the same port as the TCP server, like on the
+    // firmware.
+    let udp = Arc::new(UdpSocket::bind(listener.local_addr()?)?);
+    let source = Arc::new(args.source);
+    println!("Listening on {}...", args.listen);
+    loop {
+        let (stream, address) = listener.accept()?;
+        println!("Received connection from {address}");
+        std::thread::spawn({
+            let udp = udp.clone();
+            let source = source.clone();
+            move || match serve(stream, &udp, &source) {
+                Ok(()) => println!("Source exhausted, closing connection to {address}"),
+                Err(e) => println!("Connection to {address} closed: {e}"),
+            }
+        });
+    }
+}
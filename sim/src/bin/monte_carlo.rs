@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use clap::Parser;
+use common::control::{BalancingState, DEFAULT_GAINS, sub_angles};
+use common::safety::Fault;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use sim::Simulation;
+use sim::plant::{PlantParams, PlantState};
+use sim::rig::RigConfig;
+
+/// Runs many swing-up and balance episodes with randomised physical parameters, initial
+/// conditions and noise, and reports how each controller configuration copes.
+#[derive(Parser)]
+struct Args {
+    #[arg(long, default_value_t = 200, value_parser = clap::value_parser!(u64).range(1..))]
+    episodes: u64,
+    /// Simulated seconds per episode.
+    #[arg(long, default_value_t = 30.0)]
+    duration: f64,
+    /// Seconds the pendulum must stay upright at the end of an episode to count as balanced.
+    #[arg(long, default_value_t = 5.0)]
+    hold: f64,
+    /// Relative spread of the physical parameters, 0.2 means ±20%. Below 1, so that
+    /// masses and lengths stay positive.
+    #[arg(long, default_value_t = 0.2, value_parser = parse_spread)]
+    spread: f32,
+    /// Largest encoder noise standard deviation in radians.
+    #[arg(long, default_value_t = 0.005, value_parser = parse_max_noise)]
+    max_noise: f64,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Balancing gains as `k0,k1,k2`. Repeat to compare configurations, defaults to the
+    /// gains flashed on the firmware.
+    #[arg(long, value_parser = parse_gains, allow_hyphen_values = true)]
+    gains: Vec<[f32; 3]>,
+}
+
+fn parse_gains(s: &str) -> Result<[f32; 3], String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f32>().map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    values
+        .try_into()
+        .map_err(|_| "expected three comma separated gains".to_string())
+}
+
+fn parse_spread(s: &str) -> Result<f32, String> {
+    let spread: f32 = s
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+    if !(0.0..1.0).contains(&spread) {
+        return Err("expected a spread of at least 0 and below 1".to_string());
+    }
+    Ok(spread)
+}
+
+fn parse_max_noise(s: &str) -> Result<f64, String> {
+    let noise: f64 = s
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+    if !(noise >= 0.0 && noise.is_finite()) {
+        return Err("expected a standard deviation of at least 0".to_string());
+    }
+    Ok(noise)
+}
+
+/// Randomised conditions of one episode.
+#[derive(Debug, Clone, Copy)]
+struct Scenario {
+    params: PlantParams,
+    initial: PlantState,
+    config: RigConfig,
+}
+
+impl Scenario {
+    fn sample(args: &Args, episode: u64) -> Self {
+        let seed = args.seed.wrapping_mul(1_000_003).wrapping_add(episode);
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut vary =
+            |nominal: f32| nominal * rng.gen_range(1.0 - args.spread..=1.0 + args.spread);
+
+        let nominal = PlantParams::default();
+        let params = PlantParams {
+            inertia_ratio: vary(nominal.inertia_ratio),
+            wheel_time_constant: vary(nominal.wheel_time_constant),
+            radius: vary(nominal.radius),
+            damping: vary(nominal.damping),
+            ..nominal
+        };
+        let initial = PlantState {
+            pend_angle: core::f64::consts::PI + rng.gen_range(-0.3..=0.3),
+            pend_velocity: rng.gen_range(-1.0..=1.0),
+            wheel_velocity: 0.0,
+        };
+        let config = RigConfig {
+            angle_noise: rng.gen_range(0.0..=args.max_noise),
+            read_failure_rate: rng.gen_range(0.0..=0.02),
+            loop_delay: rng.gen_range(0.0005..=0.002),
+            seed,
+        };
+        Scenario {
+            params,
+            initial,
+            config,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Failure {
+    NeverBalanced,
+    /// Upright since less than `Args::hold` before the end, without having fallen.
+    BalancedTooLate,
+    LostBalance,
+    Fault(Fault),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::NeverBalanced => write!(f, "never balanced"),
+            Failure::BalancedTooLate => write!(f, "balanced too late"),
+            Failure::LostBalance => write!(f, "lost balance"),
+            Failure::Fault(fault) => write!(f, "motor cut by {fault:?}"),
+        }
+    }
+}
+
+struct Episode {
+    scenario: Scenario,
+    /// Seconds until the pendulum was upright for good, or why it never was.
+    outcome: Result<f64, Failure>,
+    peak_wheel_velocity: f64,
+}
+
+/// Number of failed episodes whose scenario is printed per configuration.
+const MAX_LISTED_FAILURES: usize = 10;
+
+/// Angle from upright within which the pendulum counts as balanced.
+const UPRIGHT: f32 = 0.25;
+
+fn run_episode(args: &Args, gains: [f32; 3], scenario: Scenario) -> Episode {
+    let mut sim = Simulation::new(scenario.params, scenario.initial, scenario.config)
+        .expect("the arguments are checked before sampling");
+    sim.control.controller.gains = gains;
+
+    let mut ever_balanced = false;
+    let mut upright_since = None;
+    let mut peak_wheel_velocity: f64 = 0.0;
+    while sim.time() < args.duration {
+        sim.step();
+        let state = sim.rig.borrow().plant.state;
+        peak_wheel_velocity = peak_wheel_velocity.max(state.wheel_velocity.abs());
+
+        let balancing = sim.control.controller.state == BalancingState::Balancing;
+        ever_balanced |= balancing;
+        if balancing && sub_angles(state.pend_angle as f32, 0.0).abs() < UPRIGHT {
+            upright_since.get_or_insert(sim.time());
+        } else {
+            upright_since = None;
+        }
+    }
+
+    let outcome = match (sim.control.guard.fault(), upright_since) {
+        (Some(fault), _) => Err(Failure::Fault(fault)),
+        (None, Some(t)) if t <= args.duration - args.hold => Ok(t),
+        (None, Some(_)) => Err(Failure::BalancedTooLate),
+        (None, _) if ever_balanced => Err(Failure::LostBalance),
+        (None, _) => Err(Failure::NeverBalanced),
+    };
+    Episode {
+        scenario,
+        outcome,
+        peak_wheel_velocity,
+    }
+}
+
+fn run_campaign(args: &Args, gains: [f32; 3]) -> Vec<Episode> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get()) as u64;
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|worker| {
+                scope.spawn(move || {
+                    (worker..args.episodes)
+                        .step_by(threads as usize)
+                        .map(|episode| {
+                            (
+                                episode,
+                                run_episode(args, gains, Scenario::sample(args, episode)),
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut episodes: Vec<_> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect();
+        episodes.sort_by_key(|(episode, _)| *episode);
+        episodes.into_iter().map(|(_, episode)| episode).collect()
+    })
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+fn report(gains: [f32; 3], episodes: &[Episode]) {
+    let mut times: Vec<f64> = episodes.iter().filter_map(|e| e.outcome.ok()).collect();
+    times.sort_by(f64::total_cmp);
+    let mut peaks: Vec<f64> = episodes.iter().map(|e| e.peak_wheel_velocity).collect();
+    peaks.sort_by(f64::total_cmp);
+
+    println!("gains {gains:?}");
+    println!(
+        "  balanced: {}/{} ({:.1}%)",
+        times.len(),
+        episodes.len(),
+        100.0 * times.len() as f64 / episodes.len() as f64
+    );
+    if !times.is_empty() {
+        println!(
+            "  time to balance: median {:.1} s, p90 {:.1} s, max {:.1} s",
+            percentile(&times, 0.5),
+            percentile(&times, 0.9),
+            times[times.len() - 1]
+        );
+    }
+    if !peaks.is_empty() {
+        println!(
+            // In the model's units, like `SafetyLimits::max_wheel_velocity`.
+            "  peak wheel speed: median {:.0}, p90 {:.0}, max {:.0}",
+            percentile(&peaks, 0.5),
+            percentile(&peaks, 0.9),
+            peaks[peaks.len() - 1]
+        );
+    }
+
+    let mut failures = BTreeMap::new();
+    for episode in episodes {
+        if let Err(failure) = episode.outcome {
+            *failures.entry(failure.to_string()).or_insert(0) += 1;
+        }
+    }
+    for (failure, count) in &failures {
+        println!("  {failure}: {count}");
+    }
+    let failed = episodes
+        .iter()
+        .enumerate()
+        .filter_map(|(index, e)| Some((index, e, e.outcome.err()?)));
+    for (index, episode, failure) in failed.take(MAX_LISTED_FAILURES) {
+        let s = &episode.scenario;
+        println!(
+            "    episode {index} {failure}: inertia ratio {:.4}, time constant {:.3}, radius {:.3}, damping {:.3}, noise {:.4}",
+            s.params.inertia_ratio,
+            s.params.wheel_time_constant,
+            s.params.radius,
+            s.params.damping,
+            s.config.angle_noise
+        );
+    }
+}
+
+fn main() {
+    let mut args = Args::parse();
+    if args.gains.is_empty() {
+        args.gains.push(DEFAULT_GAINS);
+    }
+
+    for &gains in &args.gains {
+        let episodes = run_campaign(&args, gains);
+        report(gains, &episodes);
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use clap::Parser;
+use common::control::BalancingState;
+use sim::plant::{PlantParams, PlantState};
+use sim::rig::RigConfig;
+use sim::{Simulation, write_frame};
+
+/// Runs the balancing controller against a simulated rig and writes the log messages
+/// to a file in the same framing as the firmware uses over TCP.
+#[derive(Parser)]
+struct Args {
+    /// Simulated time in seconds.
+    #[arg(long, default_value_t = 20.0)]
+    duration: f64,
+    /// Initial pendulum angle in radians, 0 is upright.
+    #[arg(long, default_value_t = 3.1)]
+    angle: f64,
+    /// Encoder noise standard deviation in radians.
+    #[arg(long, default_value_t = 0.001)]
+    noise: f64,
+    /// Delay from the start of a tick until the motor output is applied, in milliseconds.
+    #[arg(long, default_value_t = 0.8)]
+    delay_ms: f64,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    #[arg(long, default_value = "sim.bin")]
+    out: PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let config = RigConfig {
+        angle_noise: args.noise,
+        loop_delay: args.delay_ms * 1e-3,
+        seed: args.seed,
+        ..Default::default()
+    };
+    let initial = PlantState {
+        pend_angle: args.angle,
+        ..Default::default()
+    };
+    let mut sim = Simulation::new(PlantParams::default(), initial, config)?;
+
+    let mut out = BufWriter::new(File::create(&args.out)?);
+    write_frame(&mut out, &sim.hello("sim"))?;
+    let mut balanced_at = None;
+    while sim.time() < args.duration {
+        for msg in sim.step_messages() {
+            write_frame(&mut out, &msg)?;
+        }
+        if balanced_at.is_none() && sim.control.controller.state == BalancingState::Balancing {
+            balanced_at = Some(sim.time());
+        }
+    }
+
+    match balanced_at {
+        Some(t) => println!("Balancing after {t:.2} s"),
+        None => println!("Never balanced"),
+    }
+    if let Some(fault) = sim.control.guard.fault() {
+        println!("Motor cut: {fault:?}");
+    }
+    println!("Wrote {}", args.out.display());
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! `common::hal` implementations backed by a shared `Rig`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use common::SAMPLE_TIME_MS;
+use common::hal::{Clock, Motor, RotaryEncoder};
+
+use crate::rig::Rig;
+
+pub struct SimMotor {
+    pub rig: Rc<RefCell<Rig>>,
+    output: f32,
+}
+
+impl SimMotor {
+    pub fn new(rig: Rc<RefCell<Rig>>) -> Self {
+        SimMotor { rig, output: 0.0 }
+    }
+}
+
+impl Motor for SimMotor {
+    fn set_output(&mut self, output: f32) {
+        self.output = output;
+        self.rig.borrow_mut().set_output(output);
+    }
+
+    fn output(&self) -> f32 {
+        self.output
+    }
+}
+
+pub struct SimEncoder {
+    pub rig: Rc<RefCell<Rig>>,
+}
+
+#[derive(Debug)]
+pub struct ReadFailed;
+
+impl RotaryEncoder for SimEncoder {
+    type Error = ReadFailed;
+
+    async fn rotation(&mut self) -> Result<f32, ReadFailed> {
+        self.rig.borrow_mut().read_angle().ok_or(ReadFailed)
+    }
+}
+
+/// Advances the rig by one sample on every tick, so simulated time only passes while
+/// the control loop waits.
+pub struct SimClock {
+    pub rig: Rc<RefCell<Rig>>,
+    time_us: u64,
+}
+
+impl SimClock {
+    pub fn new(rig: Rc<RefCell<Rig>>) -> Self {
+        SimClock { rig, time_us: 0 }
+    }
+}
+
+impl Clock for SimClock {
+    async fn tick(&mut self) {
+        let period_us = SAMPLE_TIME_MS as u64 * 1000;
+        self.rig.borrow_mut().advance(period_us as f64 * 1e-6);
+        self.time_us += period_us;
+    }
+
+    fn now_us(&self) -> u64 {
+        self.time_us
+    }
+}
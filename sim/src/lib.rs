@@ -0,0 +1,125 @@
+pub mod devices;
+pub mod plant;
+pub mod rig;
+
+use std::cell::RefCell;
+use std::io::{BufRead, Read, Write};
+use std::rc::Rc;
+
+use common::control::{ControlLoop, Step};
+use common::hal::Clock;
+use common::{Envelope, FaultMessage, HelloMessage, LogMessage, framing};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::devices::{SimClock, SimEncoder, SimMotor};
+use crate::plant::{PlantParams, PlantState};
+use crate::rig::{Rig, RigConfig};
+
+pub type SimControlLoop = ControlLoop<SimMotor, SimEncoder, SimClock>;
+
+/// The firmware control loop running against a simulated `Rig`.
+pub struct Simulation {
+    pub rig: Rc<RefCell<Rig>>,
+    pub control: SimControlLoop,
+    /// Sequence number of the next message from `step_messages`.
+    seq: u32,
+}
+
+impl Simulation {
+    pub fn new(
+        params: PlantParams,
+        initial: PlantState,
+        config: RigConfig,
+    ) -> anyhow::Result<Self> {
+        let rig = Rc::new(RefCell::new(Rig::new(params, initial, config)?));
+        let control = ControlLoop::new(
+            SimMotor::new(rig.clone()),
+            SimEncoder { rig: rig.clone() },
+            SimClock::new(rig.clone()),
+            0.0,
+        );
+        Ok(Simulation {
+            rig,
+            control,
+            seq: 0,
+        })
+    }
+
+    /// Runs one control loop iteration. The simulated devices never block, so the
+    /// future completes on the first poll.
+    pub fn step(&mut self) -> Step {
+        futures::executor::block_on(self.control.step())
+    }
+
+    /// Runs one iteration and returns every message the firmware would publish for it,
+    /// numbered like the firmware does. The simulation never drops messages.
+    pub fn step_messages(&mut self) -> Vec<Envelope> {
+        let step = self.step();
+        let mut messages = vec![LogMessage::Controller(step.message.clone())];
+        if let Some(fault) = step.new_fault {
+            messages.push(LogMessage::Fault(FaultMessage {
+                time_ms: step.message.time_ms,
+                fault,
+            }));
+        }
+        if let Some(timing) = step.timing {
+            messages.push(LogMessage::Timing(timing));
+        }
+        if let Some(covariance) = step.covariance {
+            messages.push(LogMessage::Covariance(covariance));
+        }
+        messages
+            .into_iter()
+            .map(|msg| {
+                let seq = self.seq;
+                self.seq += 1;
+                Envelope {
+                    seq,
+                    dropped: 0,
+                    msg,
+                }
+            })
+            .collect()
+    }
+
+    /// What the firmware would greet a client with, where `binary` names the program
+    /// standing in for it.
+    pub fn hello(&self, binary: &str) -> HelloMessage {
+        HelloMessage::new(
+            binary,
+            env!("CARGO_PKG_VERSION"),
+            self.control.controller.gains,
+        )
+    }
+
+    /// Seconds of simulated time.
+    pub fn time(&self) -> f64 {
+        self.control.clock.now_us() as f64 * 1e-6
+    }
+}
+
+/// Writes `msg` framed like `firmware::server::transmitter`. Like a connection, a
+/// recording starts with a `HelloMessage` followed by `Envelope`s.
+pub fn write_frame(writer: &mut impl Write, msg: &impl Serialize) -> anyhow::Result<()> {
+    let mut payload = [0_u8; framing::MAX_MESSAGE_LEN];
+    let mut frame = [0_u8; framing::MAX_FRAME_LEN];
+    let n = framing::encode_message(msg, &mut payload, &mut frame)
+        .ok_or_else(|| anyhow::anyhow!("message does not fit in a frame"))?;
+    writer.write_all(&frame[..n])?;
+    Ok(())
+}
+
+/// Reads the next intact frame written by `write_frame`, skipping corrupt ones. Returns
+/// `None` at the end of the stream.
+pub fn read_frame<T: DeserializeOwned>(reader: &mut impl BufRead) -> anyhow::Result<Option<T>> {
+    let mut decoder = framing::Decoder::<{ framing::MAX_FRAME_LEN }>::new();
+    for byte in reader.bytes() {
+        if let Some(Ok(payload)) = decoder.push(byte?)
+            && let Ok(msg) = framing::decode_message(payload)
+        {
+            return Ok(Some(msg));
+        }
+    }
+    Ok(None)
+}
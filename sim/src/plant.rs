@@ -0,0 +1,95 @@
+use common::filter::{GRAVITY, INERTIA_RATIO, RADIUS, WHEEL_STATIC_GAIN, WHEEL_TIME_CONSTANT};
+
+/// Physical parameters of the rig, using the same units as `common::filter`.
+#[derive(Debug, Clone, Copy)]
+pub struct PlantParams {
+    pub inertia_ratio: f32,
+    pub wheel_time_constant: f32,
+    pub wheel_static_gain: f32,
+    pub radius: f32,
+    /// Viscous damping of the pendulum joint, in 1/s.
+    pub damping: f32,
+    pub gravity: f32,
+}
+
+impl Default for PlantParams {
+    fn default() -> Self {
+        PlantParams {
+            inertia_ratio: INERTIA_RATIO,
+            wheel_time_constant: WHEEL_TIME_CONSTANT,
+            wheel_static_gain: WHEEL_STATIC_GAIN,
+            radius: RADIUS,
+            damping: 0.2,
+            gravity: GRAVITY,
+        }
+    }
+}
+
+/// True state of the rig. The pendulum angle is 0 when upright and PI when hanging down.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlantState {
+    pub pend_angle: f64,
+    pub pend_velocity: f64,
+    pub wheel_velocity: f64,
+}
+
+/// Nonlinear pendulum, reaction wheel and motor dynamics.
+pub struct Plant {
+    pub params: PlantParams,
+    pub state: PlantState,
+}
+
+/// Step length of the numerical integration in seconds.
+const INTEGRATION_STEP: f64 = 0.0005;
+
+impl Plant {
+    pub fn new(params: PlantParams, state: PlantState) -> Self {
+        Plant { params, state }
+    }
+
+    /// Advances the plant `duration` seconds with the motor output `u` held constant.
+    pub fn advance(&mut self, duration: f64, u: f32) {
+        let mut remaining = duration;
+        while remaining > 0.0 {
+            let h = remaining.min(INTEGRATION_STEP);
+            self.rk4(h, u as f64);
+            remaining -= h;
+        }
+    }
+
+    fn derivative(&self, s: [f64; 3], u: f64) -> [f64; 3] {
+        let p = &self.params;
+        let [angle, velocity, wheel_velocity] = s;
+        let wheel_acc =
+            (p.wheel_static_gain as f64 * u - wheel_velocity) / p.wheel_time_constant as f64;
+        let pend_acc = p.gravity as f64 * angle.sin() / p.radius as f64
+            - p.inertia_ratio as f64 * wheel_acc
+            - p.damping as f64 * velocity;
+        [velocity, pend_acc, wheel_acc]
+    }
+
+    fn rk4(&mut self, h: f64, u: f64) {
+        let s = [
+            self.state.pend_angle,
+            self.state.pend_velocity,
+            self.state.wheel_velocity,
+        ];
+        let add =
+            |a: [f64; 3], b: [f64; 3], k: f64| [a[0] + k * b[0], a[1] + k * b[1], a[2] + k * b[2]];
+
+        let k1 = self.derivative(s, u);
+        let k2 = self.derivative(add(s, k1, h / 2.0), u);
+        let k3 = self.derivative(add(s, k2, h / 2.0), u);
+        let k4 = self.derivative(add(s, k3, h), u);
+
+        let mut next = s;
+        for i in 0..3 {
+            next[i] += h / 6.0 * (k1[i] + 2.0 * k2[i] + 2.0 * k3[i] + k4[i]);
+        }
+        self.state = PlantState {
+            pend_angle: next[0],
+            pend_velocity: next[1],
+            wheel_velocity: next[2],
+        };
+    }
+}
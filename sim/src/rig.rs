@@ -0,0 +1,125 @@
+use core::f64::consts::PI;
+
+use rand::SeedableRng;
+use rand::rngs::SmallRng;
+use rand_distr::{Distribution, Normal};
+
+use crate::plant::{Plant, PlantParams, PlantState};
+
+/// Resolution of the AS5600 magnetic encoder.
+const ENCODER_STEPS: f64 = 4096.0;
+
+/// PWM counter top of `firmware::motor::NidecMotor`.
+pub const PWM_TOP: u16 = 100;
+
+/// Non-ideal effects of the sensors, actuator and control loop.
+#[derive(Debug, Clone, Copy)]
+pub struct RigConfig {
+    /// Standard deviation of the encoder noise in radians, added before quantisation.
+    pub angle_noise: f64,
+    /// Probability that an encoder read fails.
+    pub read_failure_rate: f64,
+    /// Time from the start of a tick until the new motor output is applied, in seconds.
+    pub loop_delay: f64,
+    pub seed: u64,
+}
+
+impl Default for RigConfig {
+    fn default() -> Self {
+        RigConfig {
+            angle_noise: 0.001,
+            read_failure_rate: 0.0,
+            loop_delay: 0.0008,
+            seed: 0,
+        }
+    }
+}
+
+/// The simulated plant as seen through the encoder and the motor driver.
+pub struct Rig {
+    pub plant: Plant,
+    pub config: RigConfig,
+    /// Output currently applied to the motor after saturation and PWM quantisation.
+    applied: f32,
+    /// Output set during the current tick, applied `loop_delay` into the tick.
+    pending: f32,
+    rng: SmallRng,
+    noise: Normal<f64>,
+}
+
+impl RigConfig {
+    /// Checks that the settings describe a rig that can exist.
+    pub fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.angle_noise >= 0.0 && self.angle_noise.is_finite(),
+            "the encoder noise must be a standard deviation of at least 0, not {}",
+            self.angle_noise
+        );
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&self.read_failure_rate),
+            "the read failure rate must be a probability from 0 to 1, not {}",
+            self.read_failure_rate
+        );
+        anyhow::ensure!(
+            self.loop_delay >= 0.0,
+            "the loop delay must be at least 0, not {}",
+            self.loop_delay
+        );
+        Ok(())
+    }
+}
+
+impl Rig {
+    pub fn new(
+        params: PlantParams,
+        initial: PlantState,
+        config: RigConfig,
+    ) -> anyhow::Result<Self> {
+        config.check()?;
+        Ok(Rig {
+            plant: Plant::new(params, initial),
+            config,
+            applied: 0.0,
+            pending: 0.0,
+            rng: SmallRng::seed_from_u64(config.seed),
+            noise: Normal::new(0.0, config.angle_noise)?,
+        })
+    }
+
+    /// Reads the pendulum angle like `MagneticEncoder`, relative to upright and in [-PI, PI).
+    pub fn read_angle(&mut self) -> Option<f32> {
+        if rand::Rng::gen_bool(&mut self.rng, self.config.read_failure_rate) {
+            return None;
+        }
+        let angle = self.plant.state.pend_angle + self.noise.sample(&mut self.rng);
+        let raw = (angle.rem_euclid(2.0 * PI) * ENCODER_STEPS / (2.0 * PI)) as u16 % 4096;
+        let mut angle = raw as f32 * (core::f32::consts::PI * 2.0 / 4096.0);
+        if angle >= core::f32::consts::PI {
+            angle -= 2.0 * core::f32::consts::PI;
+        }
+        Some(angle)
+    }
+
+    /// Converts `output` to what the PWM peripheral can produce, like `NidecMotor::set_output`.
+    fn quantise(output: f32) -> f32 {
+        let duty = (output.abs().clamp(0.0, 1.0) * PWM_TOP as f32) as u16;
+        duty as f32 / PWM_TOP as f32 * if output < 0.0 { -1.0 } else { 1.0 }
+    }
+
+    /// Sets the motor output, it reaches the plant `loop_delay` after the start of the tick.
+    pub fn set_output(&mut self, output: f32) {
+        self.pending = output;
+    }
+
+    /// Simulates one tick of `dt` seconds.
+    pub fn advance(&mut self, dt: f64) {
+        let delay = self.config.loop_delay.min(dt);
+        self.plant.advance(delay, self.applied);
+        self.applied = Self::quantise(self.pending);
+        self.plant.advance(dt - delay, self.applied);
+    }
+
+    pub fn applied_output(&self) -> f32 {
+        self.applied
+    }
+}
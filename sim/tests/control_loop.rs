@@ -0,0 +1,86 @@
+use common::control::{BalancingState, Mode, sub_angles};
+use common::{CommandError, CommandMessage};
+use sim::Simulation;
+use sim::plant::{PlantParams, PlantState};
+use sim::rig::RigConfig;
+
+fn run(sim: &mut Simulation, seconds: f64) {
+    while sim.time() < seconds {
+        sim.step();
+    }
+}
+
+#[test]
+fn swings_up_and_balances() {
+    let initial = PlantState {
+        pend_angle: 3.1,
+        ..Default::default()
+    };
+    let mut sim = Simulation::new(PlantParams::default(), initial, RigConfig::default()).unwrap();
+
+    run(&mut sim, 30.0);
+    assert_eq!(sim.control.controller.state, BalancingState::Balancing);
+
+    // Stay up for another few seconds.
+    for _ in 0..500 {
+        sim.step();
+        assert_eq!(sim.control.controller.state, BalancingState::Balancing);
+        let angle = sim.rig.borrow().plant.state.pend_angle as f32;
+        assert!(sub_angles(angle, 0.0).abs() < 0.2);
+    }
+    assert_eq!(sim.control.guard.fault(), None);
+}
+
+#[test]
+fn keeps_estimating_through_failed_reads() {
+    let initial = PlantState {
+        pend_angle: 3.1,
+        ..Default::default()
+    };
+    let config = RigConfig {
+        read_failure_rate: 0.2,
+        ..Default::default()
+    };
+    let mut sim = Simulation::new(PlantParams::default(), initial, config).unwrap();
+
+    run(&mut sim, 30.0);
+    let estimate = sim.control.estimator.x()[1];
+    let truth = sim.rig.borrow().plant.state.pend_angle as f32;
+    assert!(
+        sub_angles(estimate, truth).abs() < 0.05,
+        "{estimate} vs {truth}"
+    );
+}
+
+#[test]
+fn follows_commands() {
+    let initial = PlantState {
+        pend_angle: 3.1,
+        ..Default::default()
+    };
+    let mut sim = Simulation::new(PlantParams::default(), initial, RigConfig::default()).unwrap();
+
+    assert_eq!(
+        sim.control.apply(&CommandMessage::SetMotorOutput(0.3)),
+        Err(CommandError::NotInBenchMode)
+    );
+    sim.control.apply(&CommandMessage::EnterBenchMode).unwrap();
+    assert_eq!(
+        sim.control.apply(&CommandMessage::SetMotorOutput(1.5)),
+        Err(CommandError::InvalidValue)
+    );
+    sim.control
+        .apply(&CommandMessage::SetMotorOutput(0.3))
+        .unwrap();
+    run(&mut sim, 1.0);
+    assert_eq!(sim.control.status().mode, Mode::Bench(0.3));
+    assert!((sim.rig.borrow().applied_output() - 0.3).abs() < 1e-6);
+
+    sim.control.apply(&CommandMessage::StopMotor).unwrap();
+    run(&mut sim, 2.0);
+    assert_eq!(sim.control.status().output, 0.0);
+
+    sim.control.apply(&CommandMessage::StartSwingUp).unwrap();
+    run(&mut sim, 32.0);
+    assert_eq!(sim.control.controller.state, BalancingState::Balancing);
+}
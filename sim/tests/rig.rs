@@ -0,0 +1,35 @@
+use sim::plant::{PlantParams, PlantState};
+use sim::rig::{Rig, RigConfig};
+
+fn rig(config: RigConfig) -> anyhow::Result<Rig> {
+    Rig::new(PlantParams::default(), PlantState::default(), config)
+}
+
+#[test]
+fn rejects_impossible_settings() {
+    assert!(rig(RigConfig::default()).is_ok());
+    for config in [
+        RigConfig {
+            angle_noise: -0.001,
+            ..Default::default()
+        },
+        RigConfig {
+            angle_noise: f64::NAN,
+            ..Default::default()
+        },
+        RigConfig {
+            read_failure_rate: 1.5,
+            ..Default::default()
+        },
+        RigConfig {
+            read_failure_rate: -0.1,
+            ..Default::default()
+        },
+        RigConfig {
+            loop_delay: -0.001,
+            ..Default::default()
+        },
+    ] {
+        assert!(rig(config).is_err(), "{config:?}");
+    }
+}